@@ -0,0 +1,62 @@
+//! Compares feeding the BS.1770 pre-filter frame by frame with feeding it
+//! blocks of frames. Run with `cargo bench`.
+
+// The crate has no library target, so the bench builds the module itself.
+#[allow(unused)]
+#[path = "../src/audio/mod.rs"]
+mod audio;
+
+use audio::bs1770::PreFilter;
+use audio::Channel;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 20;
+const BLOCK_FRAMES: usize = 4096;
+
+fn filter(sample_rate: u32, layout: &[Channel]) -> PreFilter {
+    let mut filter = PreFilter::new(sample_rate, layout);
+    filter.add_block(0.4, 4);
+    filter.add_block(3.0, 30);
+    filter
+}
+
+fn by_frame(layout: &[Channel], samples: &[f64]) {
+    let mut filter = filter(48000, layout);
+    for frame in samples.chunks_exact(layout.len()) {
+        filter.add_sample(frame);
+    }
+    filter.flush();
+}
+
+fn by_block(layout: &[Channel], samples: &[f64]) {
+    let mut filter = filter(48000, layout);
+    for block in samples.chunks(BLOCK_FRAMES * layout.len()) {
+        filter.add_frames(block);
+    }
+    filter.flush();
+}
+
+fn time(f: impl Fn()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f();
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    for channels in [2, 6] {
+        let layout = Channel::default_layout(channels);
+        // A minute of a 1 kHz sine at 48 kHz.
+        let samples = (0..48000 * 60 * channels)
+            .map(|i| 0.25 * (2.0 * std::f64::consts::PI * (i / channels) as f64 / 48.0).sin())
+            .collect::<Vec<_>>();
+
+        let by_frame = time(|| by_frame(&layout, &samples));
+        let by_block = time(|| by_block(&layout, &samples));
+        println!(
+            "{channels} channels: by frame {by_frame:?}, by block {by_block:?} ({:.2}x)",
+            by_frame.as_secs_f64() / by_block.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Compares analyzing files frame by frame with analyzing them in blocks of
+//! frames. Run with `cargo bench`.
+
+// The crate has no library target, so the bench builds the module itself.
+#[allow(unused)]
+#[path = "../src/audio/mod.rs"]
+mod audio;
+
+use audio::{Analyzer, FrameLayout, PeakMode, Registry};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 50;
+const BLOCK_FRAMES: usize = 4096;
+
+fn by_frame(registry: &Registry, path: &Path) {
+    let mut reader = registry.open(path).unwrap();
+    let mut analyzer = Analyzer::new(
+        reader.sampling_rate(),
+        &reader.channel_layout(),
+        PeakMode::True,
+    );
+    loop {
+        let mut frame = vec![0.0; reader.channels()];
+        if reader.read_frames(&mut frame).unwrap() == 0 {
+            break;
+        }
+        analyzer.add_frames(&frame, FrameLayout::Interleaved);
+    }
+    analyzer.flush();
+}
+
+fn by_block(registry: &Registry, path: &Path) {
+    let mut reader = registry.open(path).unwrap();
+    let channels = reader.channels();
+    let mut analyzer = Analyzer::new(
+        reader.sampling_rate(),
+        &reader.channel_layout(),
+        PeakMode::True,
+    );
+    let mut buffer = vec![0.0; BLOCK_FRAMES * channels];
+    loop {
+        let frames = reader.read_frames(&mut buffer).unwrap();
+        if frames == 0 {
+            break;
+        }
+        analyzer.add_frames(&buffer[..frames * channels], reader.frame_layout());
+    }
+    analyzer.flush();
+}
+
+fn time(f: impl Fn()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f();
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let registry = Registry::default();
+    for name in ["sample.flac", "sample.wav"] {
+        let path = Path::new("test_data").join(name);
+        let by_frame = time(|| by_frame(&registry, &path));
+        let by_block = time(|| by_block(&registry, &path));
+        println!(
+            "{name}: by frame {by_frame:?}, by block {by_block:?} ({:.2}x)",
+            by_frame.as_secs_f64() / by_block.as_secs_f64()
+        );
+    }
+}
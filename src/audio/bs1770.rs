@@ -1,7 +1,9 @@
 #![allow(unused)]
 
+use super::decoder::Channel;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
 use std::fmt;
 use std::ops::{Add, AddAssign, Div, Mul, Sub};
 
@@ -116,8 +118,8 @@ impl Loudness {
     pub const MIN: Loudness = Loudness(-70.0);
     pub const MAX: Loudness = Loudness(5.0);
 
-    pub fn to_gain(self) -> f64 {
-        -18.0 - self.0
+    pub fn to_gain(self, reference: f64) -> f64 {
+        reference - self.0
     }
 }
 
@@ -265,18 +267,14 @@ impl Biquad {
     }
 }
 
-struct Bin {
-    db: Loudness,
-    count: usize,
-}
-
+#[derive(Debug, Clone, PartialEq)]
 pub struct Stats {
     max_wmsq: Power,
 
     pass1_wmsq: Power,  // cumulative moving average.
     pass1_count: usize, // number of blocks processed.
 
-    bins: BTreeMap<Power, Bin>,
+    bins: Vec<usize>, // number of blocks per bin, see `bin_db`.
 }
 
 impl Stats {
@@ -284,21 +282,48 @@ impl Stats {
     const BIN_COUNT: usize = (Self::GRAIN * (Loudness::MAX.0 - Loudness::MIN.0) + 1.0) as usize;
 
     pub fn new() -> Self {
-        let step = 1.0 / Self::GRAIN;
-        let mut bins = BTreeMap::new();
-        for i in 0..Self::BIN_COUNT {
-            let db = Loudness::MIN + i as f64 * step;
-            bins.insert(db.into(), Bin { db, count: 0 });
-        }
-
         Stats {
             max_wmsq: Power::MIN,
             pass1_wmsq: Power(0.0),
             pass1_count: 0,
-            bins,
+            bins: vec![0; Self::BIN_COUNT],
         }
     }
 
+    /// Returns the lower bound of the bin `i`.
+    fn bin_db(i: usize) -> Loudness {
+        Loudness::MIN + i as f64 * (1.0 / Self::GRAIN)
+    }
+
+    fn bin_wmsq(i: usize) -> Power {
+        Self::bin_db(i).into()
+    }
+
+    /// Returns the bin of the highest lower bound not above `wmsq`, if any.
+    fn bin_index(wmsq: Power) -> Option<usize> {
+        let db = Loudness::from(wmsq);
+        let guess = ((db.0 - Loudness::MIN.0) * Self::GRAIN).floor();
+        let mut i = guess.clamp(0.0, (Self::BIN_COUNT - 1) as f64) as usize;
+
+        // The guess may be off by one where rounding differs from the bounds.
+        while i + 1 < Self::BIN_COUNT && Self::bin_wmsq(i + 1) <= wmsq {
+            i += 1;
+        }
+        while Self::bin_wmsq(i) > wmsq {
+            i = i.checked_sub(1)?;
+        }
+        Some(i)
+    }
+
+    /// Iterates over the non-empty bins above `threshold` in ascending order.
+    fn gated_bins(&self, threshold: Power) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.bins
+            .iter()
+            .enumerate()
+            .filter(move |(i, count)| 0 < **count && threshold < Self::bin_wmsq(*i))
+            .map(|(i, count)| (i, *count))
+    }
+
     pub fn merge(&mut self, rhs: &Self) {
         if self.max_wmsq < rhs.max_wmsq {
             self.max_wmsq = rhs.max_wmsq;
@@ -311,8 +336,8 @@ impl Stats {
             self.pass1_count = count;
             self.pass1_wmsq = self.pass1_wmsq * q1 + rhs.pass1_wmsq * q2;
 
-            for ((_, l), (_, r)) in self.bins.iter_mut().zip(&rhs.bins) {
-                l.count += r.count;
+            for (l, r) in self.bins.iter_mut().zip(&rhs.bins) {
+                *l += r;
             }
         }
     }
@@ -322,10 +347,10 @@ impl Stats {
             self.max_wmsq = wmsq;
         }
 
-        if let Some((_, bin)) = self.bins.range_mut(..=wmsq).last() {
+        if let Some(i) = Self::bin_index(wmsq) {
             self.pass1_count += 1;
             self.pass1_wmsq += (wmsq - self.pass1_wmsq) / self.pass1_count;
-            bin.count += 1;
+            self.bins[i] += 1;
         }
     }
 
@@ -336,11 +361,9 @@ impl Stats {
     pub fn get_mean(&self, gate: f64) -> Loudness {
         let threshold = self.pass1_wmsq.gate(gate);
         let (wmsq, count) = self
-            .bins
-            .iter()
-            .filter(|(x, bin)| 0 < bin.count && threshold < **x)
-            .fold((Power(0.0), 0), |a, (x, bin)| {
-                (a.0 + *x * bin.count, a.1 + bin.count)
+            .gated_bins(threshold)
+            .fold((Power(0.0), 0), |a, (i, count)| {
+                (a.0 + Self::bin_wmsq(i) * count, a.1 + count)
             });
 
         if 0 < count {
@@ -350,35 +373,41 @@ impl Stats {
         }
     }
 
+    /// Returns the loudness range as specified by EBU Tech 3342, assuming the statistics were
+    /// collected from 3 s short-term blocks.
+    pub fn get_loudness_range(&self) -> Loudness {
+        self.get_range(-20.0, 0.10, 0.95)
+    }
+
     pub fn get_range(&self, gate: f64, lower: f64, upper: f64) -> Loudness {
         let threshold = self.pass1_wmsq.gate(gate);
         let count = self
-            .bins
-            .iter()
-            .filter(|(x, bin)| 0 < bin.count && threshold < **x)
-            .map(|(_, bin)| bin.count)
+            .gated_bins(threshold)
+            .map(|(_, count)| count)
             .sum::<usize>();
         if count == 0 {
             return Loudness(0.0);
         }
 
+        // Nearest-rank percentiles, so that short tracks with few blocks
+        // still select one of them.
         let (lower, upper) = (lower.min(upper).max(0.0), upper.max(lower).min(1.0));
-        let lower_count = (count as f64 * lower) as usize;
-        let upper_count = (count as f64 * upper) as usize;
+        let rank = |p: f64| ((count as f64 * p).ceil() as usize).clamp(1, count);
+        let (lower_count, upper_count) = (rank(lower), rank(upper));
 
-        let (_, min, max) = self.bins.iter().filter(|(x, _)| threshold < **x).fold(
+        let (_, min, max) = self.gated_bins(threshold).fold(
             (0, Loudness(0.0), Loudness(0.0)),
-            |(prev_count, min, max), (_, bin)| {
-                let count = prev_count + bin.count;
+            |(prev_count, min, max), (i, count)| {
+                let count = prev_count + count;
 
                 let min = if prev_count < lower_count && lower_count <= count {
-                    bin.db
+                    Self::bin_db(i)
                 } else {
                     min
                 };
 
                 let max = if prev_count < upper_count && upper_count <= count {
-                    bin.db
+                    Self::bin_db(i)
                 } else {
                     max
                 };
@@ -391,6 +420,53 @@ impl Stats {
     }
 }
 
+// Serialized form of Stats. Only non-empty bins are stored as (index, count) pairs.
+#[derive(Serialize, Deserialize)]
+struct StatsRepr {
+    max_wmsq: f64,
+    pass1_wmsq: f64,
+    pass1_count: usize,
+    bins: Vec<(usize, usize)>,
+}
+
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StatsRepr {
+            max_wmsq: self.max_wmsq.0,
+            pass1_wmsq: self.pass1_wmsq.0,
+            pass1_count: self.pass1_count,
+            bins: self
+                .bins
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| 0 < **count)
+                .map(|(i, count)| (i, *count))
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Stats {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = StatsRepr::deserialize(deserializer)?;
+
+        let mut stats = Stats::new();
+        stats.max_wmsq = Power(repr.max_wmsq);
+        stats.pass1_wmsq = Power(repr.pass1_wmsq);
+        stats.pass1_count = repr.pass1_count;
+
+        for (i, count) in repr.bins {
+            match stats.bins.get_mut(i) {
+                Some(bin) => *bin = count,
+                None => return Err(D::Error::custom(format!("bin index out of range: {i}"))),
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
 // ITU BS.1770 sliding block (aggregator).
 struct Block {
     stats: Stats,
@@ -404,6 +480,7 @@ struct Block {
     ring_count: usize,     // number of samples processed in front block.
     ring_offs: usize,      // offset of front block.
     ring_wmsq: Vec<Power>, // allocated blocks.
+    ring_wssqs: f64,       // weighted sum of squares in front block.
 }
 
 impl Block {
@@ -420,47 +497,106 @@ impl Block {
             ring_count: 0,
             ring_offs: 0,
             ring_wmsq: vec![Power(0.0); partition],
+            ring_wssqs: 0.0,
+        }
+    }
+
+    /// Adds the weighted sums of squares of consecutive frames. The sums are
+    /// only added to the overlapping blocks once a partition is complete, so
+    /// the result does not depend on how the frames are split into calls.
+    fn add_sqs(&mut self, wssqs: &[f64]) {
+        let mut wssqs = wssqs;
+        while !wssqs.is_empty() {
+            let (head, tail) =
+                wssqs.split_at((self.overlap_size - self.ring_count).min(wssqs.len()));
+            self.ring_wssqs = head.iter().fold(self.ring_wssqs, |sum, x| sum + x);
+            self.ring_count += head.len();
+            if self.ring_count == self.overlap_size {
+                self.next_partition();
+            }
+            wssqs = tail;
         }
     }
 
-    fn add_sqs(&mut self, wssqs: Power) {
-        let wssqs_scaled = wssqs * self.scale;
+    fn next_partition(&mut self) {
+        let wmsq = Power(self.ring_wssqs) * self.scale;
         for i in 0..self.ring_used {
-            self.ring_wmsq[i] += wssqs_scaled;
+            self.ring_wmsq[i] += wmsq;
         }
+        self.ring_wssqs = 0.0;
 
-        self.ring_count += 1;
-        if self.ring_count == self.overlap_size {
-            let next_offs = if self.ring_offs + 1 < self.ring_size {
-                self.ring_offs + 1
-            } else {
-                0
-            };
+        let next_offs = if self.ring_offs + 1 < self.ring_size {
+            self.ring_offs + 1
+        } else {
+            0
+        };
 
-            if self.ring_used == self.ring_size {
-                let prev_wmsq = self.ring_wmsq[next_offs];
-                if self.gate < prev_wmsq {
-                    self.stats.add_sqs(prev_wmsq);
-                }
+        if self.ring_used == self.ring_size {
+            let prev_wmsq = self.ring_wmsq[next_offs];
+            if self.gate < prev_wmsq {
+                self.stats.add_sqs(prev_wmsq);
             }
+        }
 
-            self.ring_wmsq[next_offs] = Power(0.0);
-            self.ring_count = 0;
-            self.ring_offs = next_offs;
+        self.ring_wmsq[next_offs] = Power(0.0);
+        self.ring_count = 0;
+        self.ring_offs = next_offs;
 
-            if self.ring_used < self.ring_size {
-                self.ring_used += 1;
-            }
+        if self.ring_used < self.ring_size {
+            self.ring_used += 1;
         }
     }
 }
 
+// Indices into a PreFilter ring buffer of the input, the first filter's output
+// and the second filter's output, `i` samples from `offs`.
+#[inline]
+fn x_(offs: isize, i: isize) -> usize {
+    if offs + i < 0 {
+        (PreFilter::BUF_SIZE as isize + offs + i) as usize
+    } else {
+        (offs + i) as usize
+    }
+}
+#[inline]
+fn y_(offs: isize, i: isize) -> usize {
+    x_(offs - 6, i)
+}
+#[inline]
+fn z_(offs: isize, i: isize) -> usize {
+    x_(offs - 3, i)
+}
+
+/// Filter state of a channel: the input, the first filter's output and the
+/// second filter's output at `[n-1, n-2]`.
+#[derive(Clone, Copy)]
+struct ChannelState {
+    x: [f64; 2],
+    y: [f64; 2],
+    z: [f64; 2],
+}
+
+// Channel weights of ITU BS.1770: the LFE channel is excluded and the
+// surround channels are weighted +1.5 dB.
+fn channel_weight(channel: Channel) -> f64 {
+    match channel {
+        Channel::LowFrequency => 0.0,
+        Channel::BackLeft
+        | Channel::BackRight
+        | Channel::BackCenter
+        | Channel::SideLeft
+        | Channel::SideRight => 1.41,
+        _ => 1.0,
+    }
+}
+
 // ITU BS.1770 pre-filter.
 pub struct PreFilter {
     block: Vec<Block>,
 
     sample_rate: u32,
     channels: usize,
+    weights: Vec<f64>,
 
     f1: Biquad,
     f2: Biquad,
@@ -468,19 +604,22 @@ pub struct PreFilter {
     ring_offs: isize,
     ring_size: usize,
     ring_buf: Vec<[Power; Self::BUF_SIZE]>,
+
+    // Scratch buffers of add_frames.
+    state: Vec<ChannelState>,
+    wssqs: Vec<f64>,
 }
 
 impl PreFilter {
     const BUF_SIZE: usize = 9;
-    const MAX_CHANNELS: usize = 5;
-    const CHANNEL_WEIGHTS: [f64; Self::MAX_CHANNELS] = [1.0, 1.0, 1.0, 1.41, 1.41];
 
-    pub fn new(sample_rate: u32, channels: usize) -> Self {
-        let channels = channels.min(Self::MAX_CHANNELS);
+    pub fn new(sample_rate: u32, layout: &[Channel]) -> Self {
+        let channels = layout.len();
         Self {
             block: Vec::new(),
             sample_rate,
             channels,
+            weights: layout.iter().map(|c| channel_weight(*c)).collect(),
 
             f1: Biquad::f1_48000().re_quantize(sample_rate),
             f2: Biquad::f2_48000().re_quantize(sample_rate),
@@ -488,32 +627,18 @@ impl PreFilter {
             ring_offs: 1,
             ring_size: 1,
             ring_buf: vec![[Power(0.0); Self::BUF_SIZE]; channels],
+
+            state: Vec::new(),
+            wssqs: Vec::new(),
         }
     }
 
     pub fn add_block(&mut self, length: f64, partition: usize) {
         let overlap_size = (length * self.sample_rate as f64 / partition as f64).round() as usize;
-        self.block.push(Block::new(overlap_size, partition));
+        self.block.push(Block::new(overlap_size.max(1), partition));
     }
 
     pub fn add_sample(&mut self, sample: &[f64]) {
-        #[inline]
-        fn x_(offs: isize, i: isize) -> usize {
-            if offs + i < 0 {
-                (PreFilter::BUF_SIZE as isize + offs + i) as usize
-            } else {
-                (offs + i) as usize
-            }
-        }
-        #[inline]
-        fn y_(offs: isize, i: isize) -> usize {
-            x_(offs - 6, i)
-        }
-        #[inline]
-        fn z_(offs: isize, i: isize) -> usize {
-            x_(offs - 3, i)
-        }
-
         let f1 = &self.f1;
         let f2 = &self.f2;
         let offs = self.ring_offs;
@@ -539,12 +664,12 @@ impl PreFilter {
                         - buf[z_(offs, -2)] * f2.a2;
                 let z = buf[z_(offs, 0)];
 
-                wssqs += z * z * Self::CHANNEL_WEIGHTS[ch];
+                wssqs += z * z * self.weights[ch];
             }
         }
 
         for block in &mut self.block {
-            block.add_sqs(wssqs);
+            block.add_sqs(&[wssqs.0]);
         }
 
         if self.ring_size < 2 {
@@ -557,11 +682,312 @@ impl PreFilter {
         }
     }
 
+    /// Adds a block of interleaved frames of the channel count the filter was
+    /// created for. Equivalent to `add_sample` for each frame, but the filter
+    /// state stays in locals rather than the ring buffer, the recurrences of
+    /// the channels are independent so the CPU overlaps them, and the gating
+    /// blocks take the sums of squares in one pass.
+    pub fn add_frames(&mut self, samples: &[f64]) {
+        let mut samples = samples;
+        if self.ring_size < 2 {
+            // The first sample only primes the filters.
+            let Some(frame) = samples.get(..self.channels) else {
+                return;
+            };
+            self.add_sample(frame);
+            samples = &samples[self.channels..];
+        }
+
+        let frames = samples.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+
+        let offs = self.ring_offs;
+        let next_offs = (offs + frames as isize) % Self::BUF_SIZE as isize;
+
+        // Pick up the state left by add_sample or the previous block.
+        self.state.clear();
+        self.state
+            .extend(self.ring_buf.iter().map(|buf| ChannelState {
+                x: [buf[x_(offs, -1)].0, buf[x_(offs, -2)].0],
+                y: [buf[y_(offs, -1)].0, buf[y_(offs, -2)].0],
+                z: [buf[z_(offs, -1)].0, buf[z_(offs, -2)].0],
+            }));
+
+        // The same expressions as in add_sample, so the results are identical.
+        let (f1, f2) = (&self.f1, &self.f2);
+        self.wssqs.clear();
+        for frame in samples.chunks_exact(self.channels) {
+            let mut wssqs = 0.0;
+            for ((&x, s), weight) in frame.iter().zip(&mut self.state).zip(&self.weights) {
+                let y =
+                    x * f1.b0 + s.x[0] * f1.b1 + s.x[1] * f1.b2 - s.y[0] * f1.a1 - s.y[1] * f1.a2;
+                let z =
+                    y * f2.b0 + s.y[0] * f2.b1 + s.y[1] * f2.b2 - s.z[0] * f2.a1 - s.z[1] * f2.a2;
+                *s = ChannelState {
+                    x: [x, s.x[0]],
+                    y: [y, s.y[0]],
+                    z: [z, s.z[0]],
+                };
+                wssqs += z * z * weight;
+            }
+            self.wssqs.push(wssqs);
+        }
+
+        // Leave the state for add_sample or the next block.
+        for (buf, s) in self.ring_buf.iter_mut().zip(&self.state) {
+            for (i, n) in [(-1, 0), (-2, 1)] {
+                buf[x_(next_offs, i)] = Power(s.x[n]);
+                buf[y_(next_offs, i)] = Power(s.y[n]);
+                buf[z_(next_offs, i)] = Power(s.z[n]);
+            }
+        }
+
+        for block in &mut self.block {
+            block.add_sqs(&self.wssqs);
+        }
+
+        self.ring_offs = next_offs;
+    }
+
     pub fn flush(mut self) -> Vec<Stats> {
         if 1 < self.ring_size {
-            self.add_sample(&[0.0; Self::MAX_CHANNELS]);
+            self.add_sample(&vec![0.0; self.channels]);
         }
 
         self.block.into_iter().map(|b| b.stats).collect()
     }
 }
+
+// ITU BS.1770-4 Annex 2 true-peak meter.
+pub struct TruePeak {
+    ring_buf: Vec<[f64; Self::TAPS]>,
+    peak: f64,
+}
+
+impl TruePeak {
+    const TAPS: usize = 12;
+    const PHASES: [[f64; Self::TAPS]; 4] = [
+        [
+            0.0017089843750,
+            0.0109863281250,
+            -0.0196533203125,
+            0.0332031250000,
+            -0.0594482421875,
+            0.1373291015625,
+            0.9721679687500,
+            -0.1022949218750,
+            0.0476074218750,
+            -0.0266113281250,
+            0.0148925781250,
+            -0.0083007812500,
+        ],
+        [
+            -0.0291748046875,
+            0.0292968750000,
+            -0.0517578125000,
+            0.0891113281250,
+            -0.1665039062500,
+            0.4650878906250,
+            0.7797851562500,
+            -0.2003173828125,
+            0.1015625000000,
+            -0.0582275390625,
+            0.0330810546875,
+            -0.0189208984375,
+        ],
+        [
+            -0.0189208984375,
+            0.0330810546875,
+            -0.0582275390625,
+            0.1015625000000,
+            -0.2003173828125,
+            0.7797851562500,
+            0.4650878906250,
+            -0.1665039062500,
+            0.0891113281250,
+            -0.0517578125000,
+            0.0292968750000,
+            -0.0291748046875,
+        ],
+        [
+            -0.0083007812500,
+            0.0148925781250,
+            -0.0266113281250,
+            0.0476074218750,
+            -0.1022949218750,
+            0.9721679687500,
+            0.1373291015625,
+            -0.0594482421875,
+            0.0332031250000,
+            -0.0196533203125,
+            0.0109863281250,
+            0.0017089843750,
+        ],
+    ];
+
+    pub fn new(channels: usize) -> Self {
+        Self {
+            ring_buf: vec![[0.0; Self::TAPS]; channels],
+            peak: 0.0,
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: &[f64]) {
+        for (buf, x) in self.ring_buf.iter_mut().zip(sample) {
+            // The newest sample is at the front.
+            buf.copy_within(..Self::TAPS - 1, 1);
+            buf[0] = *x;
+
+            self.peak = self.peak.max(x.abs());
+            for phase in &Self::PHASES {
+                let y = phase
+                    .iter()
+                    .zip(buf.iter())
+                    .map(|(h, x)| h * x)
+                    .sum::<f64>();
+                self.peak = self.peak.max(y.abs());
+            }
+        }
+    }
+
+    pub fn flush(mut self) -> f64 {
+        // Push out the remaining samples in the interpolation filter.
+        let zeros = vec![0.0; self.ring_buf.len()];
+        for _ in 0..Self::TAPS {
+            self.add_sample(&zeros);
+        }
+
+        self.peak
+    }
+}
+
+/// Converts a linear peak value to decibels relative to full scale.
+pub fn to_db(peak: f64) -> f64 {
+    20.0 * peak.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn bin_index() {
+        // The bin a tree of lower bounds would pick: the last one not above `wmsq`.
+        let search = |wmsq: Power| {
+            (0..Stats::BIN_COUNT)
+                .rev()
+                .find(|i| Stats::bin_wmsq(*i) <= wmsq)
+        };
+
+        for i in 0..Stats::BIN_COUNT {
+            let bound = Stats::bin_wmsq(i).0;
+            for x in [
+                bound,
+                f64::from_bits(bound.to_bits() - 1),
+                f64::from_bits(bound.to_bits() + 1),
+            ] {
+                assert_eq!(Stats::bin_index(Power(x)), search(Power(x)), "{x}");
+            }
+        }
+        for x in [
+            0.0,
+            1e-9,
+            Power::MIN.0 / 2.0,
+            Power::MAX.0,
+            Power::MAX.0 * 2.0,
+            1e9,
+        ] {
+            assert_eq!(Stats::bin_index(Power(x)), search(Power(x)), "{x}");
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = Stats::new();
+        let mut b = Stats::new();
+        for i in 0..100 {
+            a.add_sqs(Power(1e-3 * (i + 1) as f64));
+            b.add_sqs(Power(2e-3 * (i + 1) as f64));
+        }
+
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(merged.pass1_count, 200);
+        assert_eq!(merged.get_max(), b.get_max());
+        assert_eq!(merged.bins.iter().sum::<usize>(), 200);
+
+        // Merging empty statistics changes nothing.
+        let mut empty = Stats::new();
+        empty.merge(&merged);
+        assert_eq!(empty, merged);
+    }
+
+    #[test]
+    fn range_of_few_blocks() {
+        let mut stats = Stats::new();
+        for db in [-30.0, -22.0, -26.0, -24.0, -28.0] {
+            stats.add_sqs(Loudness(db).into());
+        }
+        let range = f64::from(stats.get_loudness_range());
+        assert!((range - 8.0).abs() < 0.02, "{range}");
+
+        let mut stats = Stats::new();
+        stats.add_sqs(Loudness(-20.0).into());
+        assert_eq!(f64::from(stats.get_loudness_range()), 0.0);
+    }
+
+    // A chirp with a wobbling level and some noise, different on each channel.
+    fn signal(sample_rate: u32, channels: usize, seconds: u32) -> Vec<f64> {
+        let mut seed = 1u32;
+        let frames = (sample_rate * seconds) as usize;
+        (0..frames * channels)
+            .map(|i| {
+                let (t, ch) = (
+                    (i / channels) as f64 / sample_rate as f64,
+                    (i % channels) as f64,
+                );
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = seed as f64 / u32::MAX as f64 - 0.5;
+                let level = 0.3 * (1.0 + (0.7 * t + ch).sin()) / 2.0;
+                level * (2.0 * PI * (50.0 + 400.0 * t) * t + ch).sin() + 0.01 * noise
+            })
+            .collect()
+    }
+
+    fn filter(sample_rate: u32, channels: usize) -> PreFilter {
+        let mut filter = PreFilter::new(sample_rate, &Channel::default_layout(channels));
+        filter.add_block(0.4, 4);
+        filter.add_block(3.0, 30);
+        filter
+    }
+
+    #[test]
+    fn block_filter() {
+        for (sample_rate, channels) in [(48000, 2), (44100, 1), (96000, 6)] {
+            let samples = signal(sample_rate, channels, 20);
+
+            let mut by_sample = filter(sample_rate, channels);
+            for frame in samples.chunks_exact(channels) {
+                by_sample.add_sample(frame);
+            }
+
+            // Blocks of varying size, including single frames.
+            let mut by_block = filter(sample_rate, channels);
+            let mut rest = &samples[..];
+            for frames in [1, 1, 2, 7, 4096].into_iter().cycle() {
+                let (block, tail) = rest.split_at((frames * channels).min(rest.len()));
+                by_block.add_frames(block);
+                rest = tail;
+                if rest.is_empty() {
+                    break;
+                }
+            }
+
+            // Both paths evaluate the same expressions in the same order.
+            assert_eq!(by_block.flush(), by_sample.flush());
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use super::pcm::PcmReader;
+use super::symphonia::SymphoniaReader;
+use super::FrameLayout;
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// A seekable byte stream decoders can read from.
+pub trait Source: Read + Seek + Send + Sync {}
+
+impl<T: Read + Seek + Send + Sync> Source for T {}
+
+/// Speaker position of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    Unknown,
+}
+
+impl Channel {
+    /// Speaker positions in the bit order of WAVE channel masks.
+    const MASK: [Self; 11] = [
+        Self::FrontLeft,
+        Self::FrontRight,
+        Self::FrontCenter,
+        Self::LowFrequency,
+        Self::BackLeft,
+        Self::BackRight,
+        Self::FrontLeftOfCenter,
+        Self::FrontRightOfCenter,
+        Self::BackCenter,
+        Self::SideLeft,
+        Self::SideRight,
+    ];
+
+    /// Returns the speakers of a WAVE channel mask in stream order. Channels
+    /// not covered by the mask are unknown.
+    pub fn from_mask(mask: u32, channels: usize) -> Vec<Self> {
+        Self::MASK
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & 1 << i != 0)
+            .map(|(_, c)| *c)
+            .chain(std::iter::repeat(Self::Unknown))
+            .take(channels)
+            .collect()
+    }
+
+    /// Returns the layout WAVE assumes for files without a channel mask.
+    pub fn default_layout(channels: usize) -> Vec<Self> {
+        const MASKS: [u32; 9] = [0, 0x4, 0x3, 0x7, 0x33, 0x37, 0x3F, 0x13F, 0x63F];
+        Self::from_mask(MASKS.get(channels).copied().unwrap_or(0), channels)
+    }
+}
+
+/// File formats as far as they decide how a file is tagged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Mpeg,
+    Wave,
+    Aiff,
+    Caf,
+    Flac,
+    Vorbis,
+    Opus,
+    Mp4,
+}
+
+impl Format {
+    /// Recognizes the format from the beginning of a file after any ID3v2
+    /// tag.
+    fn sniff(header: &[u8]) -> Option<Self> {
+        let format = if is_wave(header) {
+            Self::Wave
+        } else if is_aiff(header) {
+            Self::Aiff
+        } else if is_caf(header) {
+            Self::Caf
+        } else if is_flac(header) {
+            Self::Flac
+        } else if is_ogg(header, b"\x01vorbis") {
+            Self::Vorbis
+        } else if is_ogg(header, b"OpusHead") {
+            Self::Opus
+        } else if is_mp4(header) {
+            Self::Mp4
+        } else if is_mpeg_audio(header) {
+            Self::Mpeg
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+
+    fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "mp3" => Some(Self::Mpeg),
+            "wav" | "wave" => Some(Self::Wave),
+            "aif" | "aifc" | "aiff" => Some(Self::Aiff),
+            "caf" => Some(Self::Caf),
+            "flac" => Some(Self::Flac),
+            "oga" | "ogg" => Some(Self::Vorbis),
+            "opus" => Some(Self::Opus),
+            "m4a" => Some(Self::Mp4),
+            _ => None,
+        }
+    }
+}
+
+pub trait Decoder {
+    /// Opens a file. Backends that can decode from any stream need not
+    /// implement this.
+    fn open(path: impl AsRef<Path>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::from_reader(Box::new(File::open(path)?))
+    }
+
+    fn from_reader(_reader: Box<dyn Source>) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        bail!("decoding from a stream is not supported")
+    }
+
+    fn sampling_rate(&self) -> u32;
+    fn channels(&self) -> usize;
+
+    /// Returns the speaker position of each channel.
+    fn channel_layout(&self) -> Vec<Channel> {
+        Channel::default_layout(self.channels())
+    }
+
+    /// Decodes frames into `buffer`, which must hold at least one frame, and
+    /// returns the number of frames, or 0 at the end of the stream. The frames
+    /// are arranged as `frame_layout` says, planar ones with the samples of
+    /// each channel following those of the previous one without gaps.
+    /// A stream may change its format mid-way; `sampling_rate` and `channels`
+    /// describe the frames the next call returns.
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize>;
+
+    /// Returns how `read_frames` arranges the samples.
+    fn frame_layout(&self) -> FrameLayout {
+        FrameLayout::Interleaved
+    }
+}
+
+/// Frame by frame reading for tests.
+#[cfg(test)]
+pub(super) trait ReadFrame {
+    /// Returns the next frame of samples, or `None` at the end of the stream.
+    fn read(&mut self) -> Result<Option<Vec<f64>>>;
+}
+
+#[cfg(test)]
+impl<D: Decoder + ?Sized> ReadFrame for D {
+    fn read(&mut self) -> Result<Option<Vec<f64>>> {
+        let mut frame = vec![0.0; self.channels()];
+        match self.read_frames(&mut frame)? {
+            0 => Ok(None),
+            _ => Ok(Some(frame)),
+        }
+    }
+}
+
+type BoxedDecoder = Result<Box<dyn Decoder>>;
+
+/// A decoder implementation and the files it claims.
+pub struct Backend {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    sniff: fn(&[u8]) -> bool,
+    open: fn(&Path) -> BoxedDecoder,
+}
+
+impl Backend {
+    /// Creates a backend for `D`. `sniff` is given the first bytes of the
+    /// stream after any ID3v2 tag.
+    pub fn new<D: Decoder + 'static>(
+        name: &'static str,
+        extensions: &'static [&'static str],
+        sniff: fn(&[u8]) -> bool,
+    ) -> Self {
+        Self {
+            name,
+            extensions,
+            sniff,
+            open: |path| Ok(Box::new(D::open(path)?)),
+        }
+    }
+
+    fn claims(&self, ext: Option<&str>) -> bool {
+        ext.is_some_and(|ext| self.extensions.contains(&ext))
+    }
+}
+
+/// The decoder backends available to a run, in order of preference.
+pub struct Registry {
+    backends: Vec<Backend>,
+    forced: Option<usize>,
+}
+
+impl Registry {
+    /// Number of bytes passed to the sniffers.
+    const HEADER_SIZE: u64 = 64;
+
+    pub fn new() -> Self {
+        Self {
+            backends: Vec::new(),
+            forced: None,
+        }
+    }
+
+    pub fn register(&mut self, backend: Backend) {
+        self.backends.push(backend);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.backends.iter().map(|b| b.name)
+    }
+
+    /// Decodes every file with the named backend instead of choosing one per
+    /// file.
+    pub fn with_backend(mut self, name: &str) -> Result<Self> {
+        match self.backends.iter().position(|b| b.name == name) {
+            Some(index) => {
+                self.forced = Some(index);
+                Ok(self)
+            }
+            None => bail!(
+                "unknown decoder {name:?} (available: {})",
+                self.names().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+
+    /// Opens a file with the first backend that accepts it. Backends that
+    /// recognize the content are tried before those claiming the extension.
+    pub fn open(&self, path: &Path) -> BoxedDecoder {
+        let header = read_header(&mut File::open(path)?)?;
+        let ext = extension(path);
+
+        let mut error = None;
+        for backend in self.candidates(&header, ext.as_deref()) {
+            match (backend.open)(path) {
+                Ok(decoder) => {
+                    log::debug!("{}: decoding with {}", path.display(), backend.name);
+                    return Ok(decoder);
+                }
+                Err(e) => {
+                    log::debug!("{}: {}: {e}", path.display(), backend.name);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => bail!("unsupported format"),
+        }
+    }
+
+    /// Identifies the format of a file by its content, or else by its
+    /// extension, and whether a backend can decode it. Returns `None` for
+    /// files of other formats.
+    pub fn identify(&self, path: &Path) -> Result<Option<(Format, bool)>> {
+        let header = read_header(&mut File::open(path)?)?;
+        let ext = extension(path);
+
+        let format =
+            Format::sniff(&header).or_else(|| ext.as_deref().and_then(Format::from_extension));
+        Ok(format.map(|f| (f, !self.candidates(&header, ext.as_deref()).is_empty())))
+    }
+
+    fn candidates(&self, header: &[u8], ext: Option<&str>) -> Vec<&Backend> {
+        if let Some(index) = self.forced {
+            return vec![&self.backends[index]];
+        }
+
+        let sniffed = self.backends.iter().filter(|b| (b.sniff)(header));
+        let claimed = self
+            .backends
+            .iter()
+            .filter(|b| !(b.sniff)(header) && b.claims(ext));
+        sniffed.chain(claimed).collect()
+    }
+}
+
+#[cfg(not(feature = "symphonia-mp3"))]
+const SYMPHONIA_EXTENSIONS: &[&str] = &["caf", "flac", "m4a", "oga", "ogg"];
+#[cfg(feature = "symphonia-mp3")]
+const SYMPHONIA_EXTENSIONS: &[&str] = &["caf", "flac", "m4a", "mp3", "oga", "ogg"];
+
+impl Default for Registry {
+    /// Returns the native backends of the platform followed by the portable
+    /// ones.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        for backend in super::backends() {
+            registry.register(backend);
+        }
+        registry.register(Backend::new::<PcmReader>(
+            "pcm",
+            &["aif", "aifc", "aiff", "wav", "wave"],
+            |h| is_wave(h) || is_aiff(h),
+        ));
+        registry.register(Backend::new::<SymphoniaReader>(
+            "symphonia",
+            SYMPHONIA_EXTENSIONS,
+            |h| {
+                is_caf(h)
+                    || is_flac(h)
+                    || is_mp4(h)
+                    || is_ogg(h, b"\x01vorbis")
+                    || cfg!(feature = "symphonia-mp3") && is_mpeg_audio(h)
+            },
+        ));
+        registry
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+}
+
+/// Reads the beginning of a stream, skipping an ID3v2 tag.
+pub(super) fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>> {
+    let start = reader.stream_position()?;
+    let mut header = Vec::new();
+    reader
+        .by_ref()
+        .take(Registry::HEADER_SIZE)
+        .read_to_end(&mut header)?;
+
+    if header.len() >= 10 && header.starts_with(b"ID3") {
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, b| size << 7 | (*b & 0x7F) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        reader.seek(SeekFrom::Start(start + 10 + size + footer))?;
+
+        header.clear();
+        reader
+            .by_ref()
+            .take(Registry::HEADER_SIZE)
+            .read_to_end(&mut header)?;
+    }
+
+    Ok(header)
+}
+
+pub(super) fn is_wave(header: &[u8]) -> bool {
+    header.len() >= 12
+        && matches!(&header[..4], b"RIFF" | b"RF64" | b"BW64")
+        && &header[8..12] == b"WAVE"
+}
+
+pub(super) fn is_aiff(header: &[u8]) -> bool {
+    header.len() >= 12 && &header[..4] == b"FORM" && matches!(&header[8..12], b"AIFF" | b"AIFC")
+}
+
+pub(super) fn is_caf(header: &[u8]) -> bool {
+    header.starts_with(b"caff")
+}
+
+pub(super) fn is_flac(header: &[u8]) -> bool {
+    header.starts_with(b"fLaC")
+}
+
+pub(super) fn is_mp4(header: &[u8]) -> bool {
+    header.len() >= 8 && &header[4..8] == b"ftyp"
+}
+
+/// Whether the first packet of an Ogg stream starts with `magic`.
+pub(super) fn is_ogg(header: &[u8], magic: &[u8]) -> bool {
+    if header.len() < 27 || !header.starts_with(b"OggS") {
+        return false;
+    }
+    let segments = header[26] as usize;
+    header
+        .get(27 + segments..)
+        .is_some_and(|packet| packet.starts_with(magic))
+}
+
+/// Whether the stream starts with an MPEG audio frame header.
+pub(super) fn is_mpeg_audio(header: &[u8]) -> bool {
+    // Frame sync, and neither a reserved version nor a reserved layer.
+    header.len() >= 2
+        && header[0] == 0xFF
+        && header[1] & 0xE0 == 0xE0
+        && header[1] & 0x18 != 0x08
+        && header[1] & 0x06 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn header(path: &str) -> Vec<u8> {
+        read_header(&mut File::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn sniff() {
+        assert!(is_flac(&header("test_data/sample.flac")));
+        assert!(is_wave(&header("test_data/sample.wav")));
+        assert!(is_aiff(&header("test_data/sample.aiff")));
+        assert!(is_caf(&header("test_data/sample.caf")));
+        assert!(is_mp4(&header("test_data/sample.m4a")));
+        assert!(is_ogg(&header("test_data/sample.ogg"), b"\x01vorbis"));
+        assert!(is_ogg(&header("test_data/sample.opus"), b"OpusHead"));
+        assert!(!is_ogg(&header("test_data/sample.opus"), b"\x01vorbis"));
+        assert!(is_mpeg_audio(&header("test_data/sample.mp3")));
+    }
+
+    #[test]
+    fn select() {
+        let registry = Registry::default();
+
+        // The content wins over a misleading extension.
+        let header = header("test_data/sample.flac");
+        let names = |ext| {
+            registry
+                .candidates(&header, ext)
+                .iter()
+                .map(|b| b.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(Some("wav"))[..2], ["symphonia", "pcm"]);
+        assert_eq!(names(None).last(), Some(&"symphonia"));
+
+        let registry = Registry::default().with_backend("pcm").unwrap();
+        assert_eq!(registry.candidates(&header, Some("flac")).len(), 1);
+        assert!(Registry::default().with_backend("foo").is_err());
+    }
+
+    #[test]
+    fn identify() {
+        let dir = std::env::temp_dir().join(format!("chksound-{}-identify", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let copy = |from: &str, to: &str| {
+            let path = dir.join(to);
+            fs::copy(Path::new("test_data").join(from), &path).unwrap();
+            path
+        };
+
+        let registry = Registry::default();
+        let identify = |path: &Path| registry.identify(path).unwrap();
+        assert_eq!(
+            identify(&copy("sample.flac", "flac.mp3")),
+            Some((Format::Flac, true))
+        );
+        assert_eq!(
+            identify(&copy("sample.opus", "opus.ogg")).map(|(f, _)| f),
+            Some(Format::Opus)
+        );
+        assert_eq!(
+            identify(&copy("sample.m4a", "m4a")),
+            Some((Format::Mp4, true))
+        );
+
+        // Unrecognized content falls back to the extension.
+        fs::write(dir.join("text.wav"), b"not a wave file").unwrap();
+        assert_eq!(identify(&dir.join("text.wav")), Some((Format::Wave, true)));
+        fs::write(dir.join("text.txt"), b"not a wave file").unwrap();
+        assert_eq!(identify(&dir.join("text.txt")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layouts() {
+        assert_eq!(Channel::default_layout(1), [Channel::FrontCenter]);
+        assert_eq!(
+            Channel::default_layout(6)[3..],
+            [Channel::LowFrequency, Channel::BackLeft, Channel::BackRight]
+        );
+        assert_eq!(
+            Channel::from_mask(0x3, 3),
+            [Channel::FrontLeft, Channel::FrontRight, Channel::Unknown]
+        );
+    }
+}
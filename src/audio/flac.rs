@@ -0,0 +1,314 @@
+use super::vorbis_comment::VorbisComment;
+use super::{AudioFile, ReplayGain};
+use anyhow::{bail, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const PADDING: u8 = 1;
+const VORBIS_COMMENT: u8 = 4;
+
+// Padding reserved when the metadata no longer fits and the file is rewritten.
+const DEFAULT_PADDING: usize = 4096;
+const MAX_BLOCK_SIZE: usize = (1 << 24) - 1;
+
+pub struct FlacFile {
+    path: PathBuf,
+    start: u64,
+    end: u64,
+    blocks: Vec<(u8, Vec<u8>)>,
+    comment: VorbisComment,
+}
+
+impl FlacFile {
+    const NORMALIZATION: &'static str = "ITUNNORM";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(&path)?);
+
+        let mut header = [0; 10];
+        reader.read_exact(&mut header[..4])?;
+        let mut start = 0;
+        if header.starts_with(b"ID3") {
+            // Skip an ID3v2 tag some taggers put in front of the stream.
+            reader.read_exact(&mut header[4..])?;
+            let size = header[6..]
+                .iter()
+                .fold(0, |size, b| size << 7 | (*b & 0x7F) as u64);
+            start = 10 + size + if header[5] & 0x10 != 0 { 10 } else { 0 };
+            reader.seek(SeekFrom::Start(start))?;
+            reader.read_exact(&mut header[..4])?;
+        }
+        if &header[..4] != b"fLaC" {
+            bail!("not a FLAC file");
+        }
+
+        let mut end = start + 4;
+        let mut blocks = Vec::new();
+        let mut comment = None;
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            let last = header[0] & 0x80 != 0;
+            let kind = header[0] & 0x7F;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data)?;
+            end += 4 + len as u64;
+
+            match kind {
+                PADDING => {}
+                VORBIS_COMMENT if comment.is_none() => comment = Some(VorbisComment::parse(&data)?),
+                VORBIS_COMMENT => log::warn!("{}: extra VORBIS_COMMENT", path.as_ref().display()),
+                _ => blocks.push((kind, data)),
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        if blocks.first().map(|(kind, _)| *kind) != Some(0) {
+            bail!("missing STREAMINFO");
+        }
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            start,
+            end,
+            blocks,
+            comment: comment.unwrap_or_default(),
+        })
+    }
+
+    fn metadata(&self, padding: Option<usize>) -> Result<Vec<u8>> {
+        let comment = self.comment.to_bytes();
+        let mut blocks = self
+            .blocks
+            .iter()
+            .map(|(kind, data)| (*kind, data.as_slice()))
+            .collect::<Vec<_>>();
+        blocks.push((VORBIS_COMMENT, &comment));
+
+        let padding = padding.map(|len| vec![0; len]);
+        if let Some(padding) = &padding {
+            blocks.push((PADDING, padding));
+        }
+
+        let mut data = b"fLaC".to_vec();
+        for (i, (kind, block)) in blocks.iter().enumerate() {
+            if block.len() > MAX_BLOCK_SIZE {
+                bail!("metadata block too large");
+            }
+
+            let last = if i == blocks.len() - 1 { 0x80 } else { 0 };
+            data.push(last | kind);
+            data.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+            data.extend_from_slice(block);
+        }
+
+        Ok(data)
+    }
+}
+
+/// Returns the range of the audio frames, which follow the metadata blocks.
+pub(super) fn audio_data(path: &Path) -> Result<Range<u64>> {
+    let file = FlacFile::open(path)?;
+    Ok(file.end..std::fs::metadata(path)?.len())
+}
+
+impl AudioFile for FlacFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self) -> Result<()> {
+        let available = (self.end - self.start) as usize;
+        let metadata = self.metadata(None)?;
+
+        // Rewrite the metadata in place if it fits into the existing blocks
+        // and padding; otherwise the audio frames have to be moved.
+        let metadata = match available.checked_sub(metadata.len()) {
+            Some(0) => Some(metadata),
+            Some(len) if len >= 4 => Some(self.metadata(Some(len - 4))?),
+            _ => None,
+        };
+
+        if let Some(metadata) = metadata {
+            let mut file = OpenOptions::new().write(true).open(&self.path)?;
+            file.seek(SeekFrom::Start(self.start))?;
+            file.write_all(&metadata)?;
+            return Ok(());
+        }
+
+        let metadata = self.metadata(Some(DEFAULT_PADDING))?;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        super::replace_file(&self.path, |writer| {
+            io::copy(&mut (&mut reader).take(self.start), writer)?;
+            writer.write_all(&metadata)?;
+            reader.seek(SeekFrom::Start(self.end))?;
+            io::copy(&mut reader, writer)?;
+            Ok(())
+        })
+    }
+
+    fn artist(&self) -> Option<&str> {
+        self.comment.artist()
+    }
+
+    fn album(&self) -> Option<&str> {
+        self.comment.album()
+    }
+
+    fn album_artist(&self) -> Option<&str> {
+        self.comment.album_artist()
+    }
+
+    fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.comment.musicbrainz_album_id()
+    }
+
+    fn disc(&self) -> Option<u32> {
+        self.comment.disc()
+    }
+
+    fn compilation(&self) -> bool {
+        self.comment.compilation()
+    }
+
+    fn normalization(&self) -> Option<&str> {
+        self.comment.get(Self::NORMALIZATION)
+    }
+
+    fn set_normalization(&mut self, val: &str) {
+        self.comment.set(Self::NORMALIZATION, val);
+    }
+
+    fn set_replaygain(&mut self, val: &ReplayGain) {
+        self.comment.set_replaygain(val);
+    }
+
+    fn remove_normalization(&mut self) -> bool {
+        self.comment.remove(Self::NORMALIZATION)
+    }
+
+    fn remove_replaygain(&mut self) -> bool {
+        self.comment.remove_replaygain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn copy(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chksound-{}-{name}", std::process::id()));
+        fs::copy("test_data/sample.flac", &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn flac_file() {
+        let file = FlacFile::open("test_data/sample.flac").unwrap();
+        assert_eq!(file.artist(), Some("Artist"));
+        assert_eq!(file.album(), Some("Album"));
+        assert_eq!(file.album_artist(), None);
+        assert_eq!(file.musicbrainz_album_id(), None);
+        assert_eq!(file.disc(), None);
+        assert!(file.compilation());
+        assert_eq!(file.normalization(), None);
+    }
+
+    #[test]
+    fn flac_save() {
+        let path = copy("save.flac");
+        let size = fs::metadata(&path).unwrap().len();
+
+        let mut file = FlacFile::open(&path).unwrap();
+        file.set_normalization(" 00000001");
+        file.set_replaygain(&ReplayGain {
+            track_gain: -6.544,
+            track_peak: 0.9876543,
+            album_gain: 1.0,
+            album_peak: 1.0,
+            reference: -18.0,
+        });
+        file.save().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+
+        let mut file = FlacFile::open(&path).unwrap();
+        assert_eq!(file.normalization(), Some(" 00000001"));
+        assert_eq!(file.comment.get(ReplayGain::TRACK_GAIN), Some("-6.54 dB"));
+        assert_eq!(file.artist(), Some("Artist"));
+
+        file.remove_normalization();
+        file.remove_replaygain();
+        file.save().unwrap();
+
+        let file = FlacFile::open(&path).unwrap();
+        assert_eq!(file.normalization(), None);
+        assert_eq!(file.comment.get(ReplayGain::TRACK_GAIN), None);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn flac_grow() {
+        let path = copy("grow.flac");
+        let size = fs::metadata(&path).unwrap().len();
+
+        let mut file = FlacFile::open(&path).unwrap();
+        file.set_normalization(&" 00000001".repeat(200));
+        file.save().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > size);
+
+        let file = FlacFile::open(&path).unwrap();
+        assert_eq!(file.normalization(), Some(" 00000001".repeat(200).as_str()));
+        assert_eq!(
+            file.end - file.start,
+            file.metadata(None).unwrap().len() as u64 + 4 + 4096
+        );
+
+        let original = FlacFile::open("test_data/sample.flac").unwrap();
+        let audio = fs::read("test_data/sample.flac").unwrap();
+        let rewritten = fs::read(&path).unwrap();
+        assert_eq!(
+            &rewritten[file.end as usize..],
+            &audio[original.end as usize..]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn flac_rewrite_in_place() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = std::env::temp_dir().join(format!("chksound-{}-rewrite", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sample.flac");
+        fs::copy("test_data/sample.flac", &path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::write(dir.join("sample.tmp"), b"unrelated").unwrap();
+        let link = dir.join("link.flac");
+        symlink(&path, &link).unwrap();
+
+        let mut file = FlacFile::open(&link).unwrap();
+        file.set_normalization(&" 00000001".repeat(200));
+        file.save().unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        let file = FlacFile::open(&path).unwrap();
+        assert_eq!(file.normalization(), Some(" 00000001".repeat(200).as_str()));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(fs::read(dir.join("sample.tmp")).unwrap(), b"unrelated");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Sound Check value stored by iTunes as iTunNORM.
+///
+/// The value consists of ten 32-bit hexadecimal fields forming five pairs. The first field of
+/// each pair describes the track and the second describes the album.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ITunNorm {
+    /// Volume adjustment in milliwatts relative to 1/1000 W (fields 1 and 2).
+    pub gain_1000: [u32; 2],
+    /// Volume adjustment in milliwatts relative to 1/2500 W (fields 3 and 4).
+    pub gain_2500: [u32; 2],
+    /// Statistical values (fields 5 and 6).
+    pub statistics: [u32; 2],
+    /// Peak sample value relative to 32768 (fields 7 and 8).
+    pub peak: [u32; 2],
+    /// Positions of the peak samples (fields 9 and 10).
+    pub peak_position: [u32; 2],
+}
+
+impl ITunNorm {
+    const FIELDS: usize = 10;
+
+    pub fn new(track_gain: f64, album_gain: f64, track_peak: f64, album_peak: f64) -> Self {
+        Self {
+            gain_1000: [
+                adjust_gain(track_gain, 1000.0),
+                adjust_gain(album_gain, 1000.0),
+            ],
+            gain_2500: [
+                adjust_gain(track_gain, 2500.0),
+                adjust_gain(album_gain, 2500.0),
+            ],
+            peak: [to_peak(track_peak), to_peak(album_peak)],
+            ..Default::default()
+        }
+    }
+
+    /// Returns the track gain in dB.
+    pub fn track_gain(&self) -> f64 {
+        to_gain(self.gain_1000[0], 1000.0)
+    }
+
+    /// Returns the album gain in dB.
+    pub fn album_gain(&self) -> f64 {
+        to_gain(self.gain_1000[1], 1000.0)
+    }
+
+    /// Returns the linear track peak.
+    pub fn track_peak(&self) -> f64 {
+        self.peak[0] as f64 / 32768.0
+    }
+
+    /// Returns the linear album peak.
+    pub fn album_peak(&self) -> f64 {
+        self.peak[1] as f64 / 32768.0
+    }
+
+    fn fields(&self) -> [u32; Self::FIELDS] {
+        [
+            self.gain_1000[0],
+            self.gain_1000[1],
+            self.gain_2500[0],
+            self.gain_2500[1],
+            self.statistics[0],
+            self.statistics[1],
+            self.peak[0],
+            self.peak[1],
+            self.peak_position[0],
+            self.peak_position[1],
+        ]
+    }
+}
+
+impl fmt::Display for ITunNorm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for field in self.fields() {
+            write!(f, " {field:08X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ITunNorm {
+    type Err = anyhow::Error;
+
+    /// Parses a value, tolerating missing leading space, lowercase hexadecimal digits and
+    /// truncated values. Missing fields are set to zero.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = [0; Self::FIELDS];
+        let mut count = 0;
+        for field in s.trim_end_matches('\0').split_whitespace() {
+            if count == Self::FIELDS {
+                bail!("too many fields in iTunNORM: {s:?}");
+            }
+            fields[count] = match u32::from_str_radix(field, 16) {
+                Ok(v) if field.len() <= 8 => v,
+                _ => bail!("invalid field in iTunNORM: {field:?}"),
+            };
+            count += 1;
+        }
+        if count == 0 {
+            bail!("empty iTunNORM");
+        }
+
+        Ok(Self {
+            gain_1000: [fields[0], fields[1]],
+            gain_2500: [fields[2], fields[3]],
+            statistics: [fields[4], fields[5]],
+            peak: [fields[6], fields[7]],
+            peak_position: [fields[8], fields[9]],
+        })
+    }
+}
+
+fn adjust_gain(gain: f64, base: f64) -> u32 {
+    (10.0_f64.powf(-gain / 10.0) * base).round().min(65534.0) as u32
+}
+
+fn to_gain(value: u32, base: f64) -> f64 {
+    10.0 * (base / value as f64).log10()
+}
+
+fn to_peak(peak: f64) -> u32 {
+    (peak * 32768.0) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let s = " 000003E8 000007D0 000009C4 00001388 00024CA8 00024CA8 00004000 00008000 00001234 00005678";
+        let norm = s.parse::<ITunNorm>().unwrap();
+        assert_eq!(norm.gain_1000, [0x3E8, 0x7D0]);
+        assert_eq!(norm.statistics, [0x24CA8, 0x24CA8]);
+        assert_eq!(norm.peak_position, [0x1234, 0x5678]);
+        assert_eq!(norm.to_string(), s);
+    }
+
+    #[test]
+    fn parse_variants() {
+        let norm = "000003e8 000007d0".parse::<ITunNorm>().unwrap();
+        assert_eq!(norm.gain_1000, [0x3E8, 0x7D0]);
+        assert_eq!(norm.peak, [0, 0]);
+
+        assert!("".parse::<ITunNorm>().is_err());
+        assert!(" 000003E8 xyz".parse::<ITunNorm>().is_err());
+        assert!(" 1000003E8".parse::<ITunNorm>().is_err());
+        assert!(" 0 0 0 0 0 0 0 0 0 0 0".parse::<ITunNorm>().is_err());
+    }
+
+    #[test]
+    fn gain_and_peak() {
+        let norm = ITunNorm::new(-6.0, 3.0, 0.5, 1.0);
+        assert_eq!(norm.gain_1000, [3981, 501]);
+        assert_eq!(norm.gain_2500, [9953, 1253]);
+        assert_eq!(norm.peak, [0x4000, 0x8000]);
+        assert!((norm.track_gain() + 6.0).abs() < 0.01);
+        assert!((norm.album_gain() - 3.0).abs() < 0.01);
+        assert_eq!(norm.track_peak(), 0.5);
+        assert_eq!(norm.album_peak(), 1.0);
+
+        let norm = ITunNorm::new(-30.0, -30.0, 0.0, 0.0);
+        assert_eq!(norm.gain_1000, [65534, 65534]);
+    }
+}
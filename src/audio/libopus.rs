@@ -0,0 +1,305 @@
+use super::decoder::{Channel, Decoder, Source};
+use super::ogg::PacketReader;
+use anyhow::{bail, Result};
+use std::io::BufReader;
+
+/// Opus always decodes at 48 kHz.
+const SAMPLING_RATE: u32 = 48000;
+/// The longest Opus packet is 120 ms.
+const MAX_FRAME_SIZE: usize = 5760;
+
+pub struct OpusReader {
+    packets: PacketReader<BufReader<Box<dyn Source>>>,
+    decoder: ffi::Decoder,
+    channels: usize,
+    mapping_family: u8,
+    gain: f32,
+    skip: u64,
+    decoded: u64,
+    buffer: Vec<f32>,
+    len: usize,
+    position: usize,
+}
+
+impl OpusReader {
+    /// Decodes the next packet, returning `false` at the end of the stream.
+    fn decode(&mut self) -> Result<bool> {
+        let Some(packet) = self.packets.next_packet()? else {
+            return Ok(false);
+        };
+
+        let frames = self.decoder.decode(&packet, &mut self.buffer)? as u64;
+        let start = self.skip.min(frames);
+        self.skip -= start;
+
+        // The granule position of the last page trims the padding added
+        // by the encoder at the end of the stream.
+        let mut end = frames;
+        self.decoded += frames;
+        if let Some(granule) = self.packets.end() {
+            let excess = self.decoded.saturating_sub(granule);
+            end = end.saturating_sub(excess).max(start);
+        }
+
+        self.position = start as usize * self.channels;
+        self.len = end as usize * self.channels;
+        Ok(true)
+    }
+}
+
+impl Decoder for OpusReader {
+    fn from_reader(reader: Box<dyn Source>) -> Result<Self> {
+        let mut packets = PacketReader::new(BufReader::new(reader));
+
+        let Some(head) = packets.next_packet()? else {
+            bail!("missing OpusHead header");
+        };
+        if !head.starts_with(b"OpusHead") || head.len() < 19 {
+            bail!("not an Opus stream");
+        }
+        if packets.next_packet()?.is_none() {
+            bail!("missing OpusTags header");
+        }
+
+        let channels = head[9] as usize;
+        if channels == 0 {
+            bail!("invalid channel count");
+        }
+        let skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+        let output_gain = i16::from_le_bytes([head[16], head[17]]);
+        let (streams, coupled, mapping) = match head[18] {
+            0 if channels <= 2 => (1, channels as u8 - 1, vec![0, 1][..channels].to_vec()),
+            _ if head.len() >= 21 + channels => {
+                (head[19], head[20], head[21..21 + channels].to_vec())
+            }
+            _ => bail!("invalid channel mapping"),
+        };
+
+        let decoder = ffi::Decoder::new(SAMPLING_RATE, channels, streams, coupled, &mapping)?;
+
+        Ok(Self {
+            packets,
+            decoder,
+            channels,
+            mapping_family: head[18],
+            // Players apply the header output gain, so the analysis does too.
+            gain: 10f32.powf(output_gain as f32 / (20.0 * 256.0)),
+            skip,
+            decoded: 0,
+            buffer: vec![0.0; MAX_FRAME_SIZE * channels],
+            len: 0,
+            position: 0,
+        })
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        let len = buffer.len() - buffer.len() % self.channels;
+        let mut filled = 0;
+        while filled < len {
+            if self.position == self.len && !self.decode()? {
+                break;
+            }
+
+            let n = (self.len - self.position).min(len - filled);
+            let samples = &self.buffer[self.position..self.position + n];
+            for (out, sample) in buffer[filled..filled + n].iter_mut().zip(samples) {
+                *out = (*sample * self.gain) as f64;
+            }
+            self.position += n;
+            filled += n;
+        }
+        Ok(filled / self.channels)
+    }
+
+    fn sampling_rate(&self) -> u32 {
+        SAMPLING_RATE
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn channel_layout(&self) -> Vec<Channel> {
+        use Channel::*;
+
+        // Mapping families 0 and 1 use the Vorbis channel order (RFC 7845
+        // section 5.1.1.2); other families leave the layout undefined.
+        let layout: &[Channel] = match (self.mapping_family, self.channels) {
+            (0 | 1, 1) => &[FrontCenter],
+            (0 | 1, 2) => &[FrontLeft, FrontRight],
+            (1, 3) => &[FrontLeft, FrontCenter, FrontRight],
+            (1, 4) => &[FrontLeft, FrontRight, BackLeft, BackRight],
+            (1, 5) => &[FrontLeft, FrontCenter, FrontRight, BackLeft, BackRight],
+            (1, 6) => &[
+                FrontLeft,
+                FrontCenter,
+                FrontRight,
+                BackLeft,
+                BackRight,
+                LowFrequency,
+            ],
+            (1, 7) => &[
+                FrontLeft,
+                FrontCenter,
+                FrontRight,
+                SideLeft,
+                SideRight,
+                BackCenter,
+                LowFrequency,
+            ],
+            (1, 8) => &[
+                FrontLeft,
+                FrontCenter,
+                FrontRight,
+                SideLeft,
+                SideRight,
+                BackLeft,
+                BackRight,
+                LowFrequency,
+            ],
+            _ => return vec![Unknown; self.channels],
+        };
+        layout.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opus() {
+        use crate::audio::{Analyzer, PeakMode};
+
+        let mut r = OpusReader::open("test_data/sample.opus").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut analyzer = Analyzer::new(r.sampling_rate(), &r.channel_layout(), PeakMode::Sample);
+        let mut buffer = vec![0.0; 4096 * 2];
+        let mut frames = 0;
+        loop {
+            let n = r.read_frames(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            analyzer.add_frames(&buffer[..n * 2], r.frame_layout());
+            frames += n;
+        }
+        // 50 packets of 20 ms, less the pre-skip and the end trimming.
+        assert_eq!(frames, 960 * 50 - 312 - 480);
+
+        // A 1 kHz sine at -20 dBFS in both channels, which measures -20 LUFS,
+        // within what the lossy encoding changes.
+        let analysis = analyzer.flush();
+        let loudness = f64::from(analysis.stats.get_mean(-10.0));
+        assert!((loudness + 20.0).abs() < 0.2, "{loudness}");
+        assert!((analysis.peak - 0.1).abs() < 0.005, "{}", analysis.peak);
+    }
+}
+
+mod ffi {
+    #![allow(non_camel_case_types)]
+
+    use libc::{c_char, c_int, c_uchar, c_void};
+    use std::ffi::CStr;
+    use std::fmt;
+
+    #[repr(C)]
+    struct OpusMSDecoder(c_void);
+
+    const OPUS_OK: c_int = 0;
+
+    #[link(name = "opus")]
+    extern "C" {
+        fn opus_multistream_decoder_create(
+            fs: i32,
+            channels: c_int,
+            streams: c_int,
+            coupled_streams: c_int,
+            mapping: *const c_uchar,
+            error: *mut c_int,
+        ) -> *mut OpusMSDecoder;
+
+        fn opus_multistream_decoder_destroy(st: *mut OpusMSDecoder);
+
+        fn opus_multistream_decode_float(
+            st: *mut OpusMSDecoder,
+            data: *const c_uchar,
+            len: i32,
+            pcm: *mut f32,
+            frame_size: c_int,
+            decode_fec: c_int,
+        ) -> c_int;
+
+        fn opus_strerror(error: c_int) -> *const c_char;
+    }
+
+    #[derive(Debug)]
+    pub struct Error(c_int);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let msg = unsafe { CStr::from_ptr(opus_strerror(self.0)) };
+            write!(f, "{}", msg.to_string_lossy())
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    pub struct Decoder {
+        decoder: *mut OpusMSDecoder,
+        channels: usize,
+    }
+
+    impl Decoder {
+        pub fn new(
+            sampling_rate: u32,
+            channels: usize,
+            streams: u8,
+            coupled: u8,
+            mapping: &[u8],
+        ) -> Result<Self, Error> {
+            let mut error = OPUS_OK;
+            let decoder = unsafe {
+                opus_multistream_decoder_create(
+                    sampling_rate as _,
+                    channels as _,
+                    streams as _,
+                    coupled as _,
+                    mapping.as_ptr(),
+                    &mut error,
+                )
+            };
+            match error {
+                OPUS_OK => Ok(Self { decoder, channels }),
+                error => Err(Error(error)),
+            }
+        }
+
+        /// Decodes a packet into interleaved samples and returns the number
+        /// of samples per channel.
+        pub fn decode(&mut self, packet: &[u8], pcm: &mut [f32]) -> Result<usize, Error> {
+            let frame_size = pcm.len() / self.channels;
+            match unsafe {
+                opus_multistream_decode_float(
+                    self.decoder,
+                    packet.as_ptr(),
+                    packet.len() as _,
+                    pcm.as_mut_ptr(),
+                    frame_size as _,
+                    0,
+                )
+            } {
+                n if n >= 0 => Ok(n as usize),
+                error => Err(Error(error)),
+            }
+        }
+    }
+
+    impl Drop for Decoder {
+        fn drop(&mut self) {
+            unsafe { opus_multistream_decoder_destroy(self.decoder) }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use self::ffi::*;
+use super::decoder::{is_aiff, is_caf, is_mp4, is_mpeg_audio, is_wave, Backend, Decoder};
 use anyhow::{bail, Result};
 use core_foundation::base::TCFType;
 use core_foundation::url::CFURL;
@@ -8,17 +9,22 @@ use std::mem::size_of_val;
 use std::path::Path;
 use std::ptr::{addr_of, addr_of_mut, null};
 
-pub struct AudioReader {
+/// Returns Core Audio, which is preferred for the formats it decodes.
+pub fn backends() -> Vec<Backend> {
+    vec![Backend::new::<CoreAudioReader>(
+        "coreaudio",
+        &["aif", "aifc", "aiff", "caf", "m4a", "mp3", "wav", "wave"],
+        |h| is_aiff(h) || is_caf(h) || is_mp4(h) || is_mpeg_audio(h) || is_wave(h),
+    )]
+}
+
+pub struct CoreAudioReader {
     file: ExtAudioFile,
     format: AudioStreamBasicDescription,
-    frames_per_packet: u32,
-    buffer: Vec<f64>,
-    pos: usize,
-    limit: usize,
 }
 
-impl AudioReader {
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+impl Decoder for CoreAudioReader {
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
         let url = CFURL::from_path(path, false).unwrap();
         let mut file = match ExtAudioFile::open_url(&url) {
             Ok(file) => file,
@@ -30,7 +36,6 @@ impl AudioReader {
             bail!("failed to get property: {}", status)
         };
 
-        let frames_per_packet = format.mFramesPerPacket;
         format.mFormatID = kAudioFormatLinearPCM;
         format.mFormatFlags = kAudioFormatFlagsNativeFloatPacked;
         format.mFramesPerPacket = 1;
@@ -41,53 +46,32 @@ impl AudioReader {
             bail!("failed to set property: {}", status)
         };
 
-        let buffer = vec![0.0; (format.mChannelsPerFrame * frames_per_packet) as _];
-
-        Ok(Self {
-            file,
-            format,
-            frames_per_packet,
-            buffer,
-            pos: 0,
-            limit: 0,
-        })
+        Ok(Self { file, format })
     }
 
-    pub fn read(&mut self) -> Result<Option<Vec<f64>>> {
-        if self.pos == self.limit {
-            let mut buffers = AudioBufferList {
-                mNumberBuffers: 1,
-                mBuffers: [AudioBuffer {
-                    mNumberChannels: self.format.mChannelsPerFrame,
-                    mDataByteSize: self.format.mBytesPerFrame * self.frames_per_packet,
-                    mData: self.buffer.as_mut_ptr() as _,
-                }],
-            };
-            let frames = match self.file.read(self.frames_per_packet, &mut buffers) {
-                Ok(len) => len,
-                Err(status) => bail!("failed to read: {}", status),
-            };
-            self.pos = 0;
-            self.limit = frames as _;
-        }
-
-        if self.pos < self.limit {
-            let index = self.pos * self.format.mChannelsPerFrame as usize;
-            let sample =
-                self.buffer[index..index + self.format.mChannelsPerFrame as usize].to_vec();
-            self.pos += 1;
-
-            return Ok(Some(sample));
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        // The client format is interleaved f64, so Core Audio decodes
+        // straight into the caller's buffer.
+        let frames = (buffer.len() / self.channels()) as u32;
+        let mut buffers = AudioBufferList {
+            mNumberBuffers: 1,
+            mBuffers: [AudioBuffer {
+                mNumberChannels: self.format.mChannelsPerFrame,
+                mDataByteSize: self.format.mBytesPerFrame * frames,
+                mData: buffer.as_mut_ptr() as _,
+            }],
+        };
+        match self.file.read(frames, &mut buffers) {
+            Ok(len) => Ok(len as _),
+            Err(status) => bail!("failed to read: {}", status),
         }
-
-        Ok(None)
     }
 
-    pub fn sampling_rate(&self) -> u32 {
+    fn sampling_rate(&self) -> u32 {
         self.format.mSampleRate as _
     }
 
-    pub fn channels(&self) -> usize {
+    fn channels(&self) -> usize {
         self.format.mChannelsPerFrame as _
     }
 }
@@ -141,14 +125,14 @@ mod tests {
 
     #[test]
     fn test_mp3() {
-        let r = AudioReader::open("test_data/sample.mp3").unwrap();
+        let r = CoreAudioReader::open("test_data/sample.mp3").unwrap();
         assert_eq!(r.sampling_rate(), 48000);
         assert_eq!(r.channels(), 2);
     }
 
     #[test]
     fn test_m4a() {
-        let r = AudioReader::open("test_data/sample.m4a").unwrap();
+        let r = CoreAudioReader::open("test_data/sample.m4a").unwrap();
         assert_eq!(r.sampling_rate(), 48000);
         assert_eq!(r.channels(), 2);
     }
@@ -1,44 +1,152 @@
 pub mod bs1770;
+mod decoder;
+mod flac;
+mod itunnorm;
+mod ogg;
+mod opus;
+mod pcm;
+mod symphonia;
+mod vorbis_comment;
 
 use anyhow::Result;
-use bs1770::{PreFilter, Stats};
+use bs1770::{PreFilter, Stats, TruePeak};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::mem;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+pub use self::decoder::{Channel, Format, Registry};
+pub use self::flac::FlacFile;
+pub use self::itunnorm::ITunNorm;
+pub use self::ogg::OggFile;
+pub use self::opus::OpusFile;
+
 pub trait AudioFile {
     fn path(&self) -> &Path;
     fn save(&self) -> Result<()>;
 
+    /// Whether `save` can write tags to the container; files that cannot are
+    /// only analyzed.
+    fn writable(&self) -> bool {
+        true
+    }
+
     fn artist(&self) -> Option<&str>;
     fn album(&self) -> Option<&str>;
+    fn album_artist(&self) -> Option<&str>;
+    fn musicbrainz_album_id(&self) -> Option<&str>;
+    fn disc(&self) -> Option<u32>;
     fn compilation(&self) -> bool;
+
+    /// Whether the format can carry iTunNORM; files that cannot get
+    /// ReplayGain tags instead.
+    fn supports_normalization(&self) -> bool {
+        true
+    }
+
+    fn normalization(&self) -> Option<&str>;
     fn set_normalization(&mut self, val: &str);
+
+    /// Returns the tags `set_replaygain` writes.
+    fn replaygain_tags(&self, val: &ReplayGain) -> Vec<(&'static str, String)> {
+        val.tags().into()
+    }
+
+    fn set_replaygain(&mut self, val: &ReplayGain);
+    /// Removes the normalization tag and returns whether there was one.
+    fn remove_normalization(&mut self) -> bool;
+    /// Removes all ReplayGain tags and returns whether there were any.
+    fn remove_replaygain(&mut self) -> bool;
+}
+
+pub struct ReplayGain {
+    pub track_gain: f64,
+    pub track_peak: f64,
+    pub album_gain: f64,
+    pub album_peak: f64,
+    /// Reference level in LUFS the gains are relative to.
+    pub reference: f64,
+}
+
+impl ReplayGain {
+    pub const TRACK_GAIN: &'static str = "REPLAYGAIN_TRACK_GAIN";
+    pub const TRACK_PEAK: &'static str = "REPLAYGAIN_TRACK_PEAK";
+    pub const ALBUM_GAIN: &'static str = "REPLAYGAIN_ALBUM_GAIN";
+    pub const ALBUM_PEAK: &'static str = "REPLAYGAIN_ALBUM_PEAK";
+    pub const NAMES: [&'static str; 4] = [
+        Self::TRACK_GAIN,
+        Self::TRACK_PEAK,
+        Self::ALBUM_GAIN,
+        Self::ALBUM_PEAK,
+    ];
+
+    /// Reference level in LUFS players assume for ReplayGain 2.0 tags.
+    pub const REFERENCE: f64 = -18.0;
+
+    /// Returns the tag names and their values formatted as specified by ReplayGain 2.0. The
+    /// gains are converted from `reference` to the ReplayGain reference level.
+    pub fn tags(&self) -> [(&'static str, String); 4] {
+        let offset = Self::REFERENCE - self.reference;
+        [
+            (
+                Self::TRACK_GAIN,
+                format!("{:.2} dB", self.track_gain + offset),
+            ),
+            (Self::TRACK_PEAK, format!("{:.6}", self.track_peak)),
+            (
+                Self::ALBUM_GAIN,
+                format!("{:.2} dB", self.album_gain + offset),
+            ),
+            (Self::ALBUM_PEAK, format!("{:.6}", self.album_peak)),
+        ]
+    }
 }
 
-pub struct Mp3File {
+/// A file tagged with ID3v2, either prepended to an MP3 stream or stored in
+/// an `ID3 ` chunk of a WAV or AIFF container.
+pub struct Id3File {
     path: PathBuf,
+    writable: bool,
     tag: id3::Tag,
 }
 
-impl Mp3File {
+impl Id3File {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let tag = id3::Tag::read_from_path(&path)?;
+        // CAF and RF64 have no ID3 chunk id3 can update; they are analyzed
+        // and reported only.
+        let mut magic = [0; 4];
+        File::open(&path)?.read_exact(&mut magic)?;
+        let writable = !matches!(&magic, b"caff" | b"RF64" | b"BW64");
+
+        let tag = id3::Tag::read_from_path(&path);
         Ok(Self {
             path: path.as_ref().to_path_buf(),
-            tag,
+            writable,
+            tag: id3::no_tag_ok(tag)?.unwrap_or_default(),
         })
     }
 }
 
-impl AudioFile for Mp3File {
+impl AudioFile for Id3File {
     fn path(&self) -> &Path {
         &self.path
     }
 
     fn save(&self) -> Result<()> {
+        if !self.writable {
+            anyhow::bail!("writing tags to this container is not supported");
+        }
+
         self.tag.write_to_path(&self.path, id3::Version::Id3v24)?;
         Ok(())
     }
 
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
     fn artist(&self) -> Option<&str> {
         use id3::TagLike;
         self.tag.artist()
@@ -49,6 +157,23 @@ impl AudioFile for Mp3File {
         self.tag.album()
     }
 
+    fn album_artist(&self) -> Option<&str> {
+        use id3::TagLike;
+        self.tag.album_artist()
+    }
+
+    fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.tag
+            .extended_texts()
+            .find(|t| t.description == "MusicBrainz Album Id")
+            .map(|t| t.value.as_str())
+    }
+
+    fn disc(&self) -> Option<u32> {
+        use id3::TagLike;
+        self.tag.disc()
+    }
+
     fn compilation(&self) -> bool {
         use id3::TagLike;
         match self.tag.get("TCMP") {
@@ -63,6 +188,13 @@ impl AudioFile for Mp3File {
         }
     }
 
+    fn normalization(&self) -> Option<&str> {
+        self.tag
+            .comments()
+            .find(|c| c.description == "iTunNORM")
+            .map(|c| c.text.as_str())
+    }
+
     fn set_normalization(&mut self, val: &str) {
         use id3::TagLike;
         self.tag.remove_comment(Some("iTunNORM"), None);
@@ -72,6 +204,38 @@ impl AudioFile for Mp3File {
             text: val.to_string(),
         });
     }
+
+    fn set_replaygain(&mut self, val: &ReplayGain) {
+        use id3::TagLike;
+        for (name, value) in val.tags() {
+            // Lowercase frames from other taggers would hold a second value.
+            self.tag.remove_extended_text(Some(name), None);
+            self.tag
+                .remove_extended_text(Some(&name.to_ascii_lowercase()), None);
+            self.tag.add_frame(id3::frame::ExtendedText {
+                description: name.to_string(),
+                value,
+            });
+        }
+    }
+
+    fn remove_normalization(&mut self) -> bool {
+        use id3::TagLike;
+        let before = self.tag.frames().count();
+        self.tag.remove_comment(Some("iTunNORM"), None);
+        self.tag.frames().count() != before
+    }
+
+    fn remove_replaygain(&mut self) -> bool {
+        use id3::TagLike;
+        let before = self.tag.frames().count();
+        for name in ReplayGain::NAMES {
+            self.tag.remove_extended_text(Some(name), None);
+            self.tag
+                .remove_extended_text(Some(&name.to_ascii_lowercase()), None);
+        }
+        self.tag.frames().count() != before
+    }
 }
 
 pub struct M4aFile {
@@ -81,6 +245,10 @@ pub struct M4aFile {
 
 impl M4aFile {
     const COMPILATION: mp4ameta::Fourcc = mp4ameta::Fourcc(*b"cpil");
+    const NORMALIZATION: mp4ameta::FreeformIdent<'static> =
+        mp4ameta::FreeformIdent::new("com.apple.iTunes", "iTunNORM");
+    const MUSICBRAINZ_ALBUM_ID: mp4ameta::FreeformIdent<'static> =
+        mp4ameta::FreeformIdent::new("com.apple.iTunes", "MusicBrainz Album Id");
 
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let tag = mp4ameta::Tag::read_from_path(&path)?;
@@ -109,6 +277,18 @@ impl AudioFile for M4aFile {
         self.tag.album()
     }
 
+    fn album_artist(&self) -> Option<&str> {
+        self.tag.album_artist()
+    }
+
+    fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.tag.strings_of(&Self::MUSICBRAINZ_ALBUM_ID).next()
+    }
+
+    fn disc(&self) -> Option<u32> {
+        self.tag.disc_number().map(u32::from)
+    }
+
     fn compilation(&self) -> bool {
         match self.tag.data_of(&Self::COMPILATION).next() {
             Some(d) => match d.bytes() {
@@ -119,51 +299,311 @@ impl AudioFile for M4aFile {
         }
     }
 
+    fn normalization(&self) -> Option<&str> {
+        self.tag.strings_of(&Self::NORMALIZATION).next()
+    }
+
     fn set_normalization(&mut self, val: &str) {
-        self.tag.add_data(
-            mp4ameta::FreeformIdent::new("com.apple.iTunes", "iTunNORM"),
-            mp4ameta::Data::Utf8(val.to_string()),
+        self.tag
+            .set_data(Self::NORMALIZATION, mp4ameta::Data::Utf8(val.to_string()));
+    }
+
+    fn set_replaygain(&mut self, val: &ReplayGain) {
+        for (name, value) in val.tags() {
+            // Uppercase atoms from other taggers would hold a second value.
+            self.tag
+                .remove_data_of(&mp4ameta::FreeformIdent::new("com.apple.iTunes", name));
+            self.tag.set_data(
+                mp4ameta::FreeformIdent::new("com.apple.iTunes", &name.to_ascii_lowercase()),
+                mp4ameta::Data::Utf8(value),
+            );
+        }
+    }
+
+    fn remove_normalization(&mut self) -> bool {
+        self.tag.take_data_of(&Self::NORMALIZATION).count() > 0
+    }
+
+    fn remove_replaygain(&mut self) -> bool {
+        let mut removed = false;
+        for name in ReplayGain::NAMES {
+            for name in [name.to_ascii_lowercase(), name.to_string()] {
+                let ident = mp4ameta::FreeformIdent::new("com.apple.iTunes", &name);
+                removed |= self.tag.take_data_of(&ident).count() > 0;
+            }
+        }
+        removed
+    }
+}
+
+/// Replaces a file with the output of `write`. The output goes to a
+/// temporary file next to the target of `path`, which gets its permissions
+/// and is renamed over it, so symlinks are kept and the original is left
+/// intact if writing fails. A missing file is created.
+pub fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.chksound-tmp", std::process::id()));
+    let temp = path.with_file_name(name);
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+    let permissions = match fs::metadata(&path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    let res = permissions.map_err(anyhow::Error::from).and_then(|_| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    res
+}
+
+/// Returns the byte ranges of a file that hold its encoded audio. Tags and
+/// the container metadata rewritten along with them are left out, so
+/// retagging leaves the data in these ranges unchanged.
+#[allow(clippy::single_range_in_vec_init)]
+pub fn audio_data(path: &Path) -> Result<Vec<Range<u64>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let header = decoder::read_header(&mut file)?;
+    // The header was read right after any ID3v2 tag.
+    let start = file.stream_position()? - header.len() as u64;
+
+    let ranges = if decoder::is_flac(&header) {
+        flac::audio_data(path).map(|r| vec![r])
+    } else if decoder::is_ogg(&header, b"") {
+        ogg::audio_data(path)
+    } else if decoder::is_mp4(&header) {
+        symphonia::mdat_ranges(path)
+    } else if decoder::is_wave(&header) || decoder::is_aiff(&header) {
+        pcm::audio_data(path).map(|r| vec![r])
+    } else if decoder::is_mpeg_audio(&header) {
+        // An ID3v1 tag takes the last 128 bytes.
+        let mut end = len;
+        if start + 128 <= len {
+            let mut tag = [0; 3];
+            file.seek(SeekFrom::Start(len - 128))?;
+            file.read_exact(&mut tag)?;
+            if &tag == b"TAG" {
+                end -= 128;
+            }
+        }
+        Ok(vec![start..end])
+    } else {
+        Ok(vec![0..len])
+    };
+
+    Ok(ranges.unwrap_or_else(|e| {
+        log::debug!(
+            "{}: using the whole file as audio data: {e}",
+            path.display()
         );
+        vec![0..len]
+    }))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    /// Statistics of 400 ms momentary blocks used for integrated loudness.
+    pub stats: Stats,
+    /// Statistics of 3 s short-term blocks used for loudness range.
+    pub short_term: Stats,
+    /// Sample peak.
+    pub peak: f64,
+    /// True peak, if measured.
+    pub true_peak: Option<f64>,
+}
+
+impl Analysis {
+    pub fn peak(&self, mode: PeakMode) -> f64 {
+        match mode {
+            PeakMode::Sample => self.peak,
+            PeakMode::True => self.true_peak.unwrap_or(self.peak),
+        }
     }
 }
 
+/// How the samples of a block of frames are arranged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameLayout {
+    /// The samples of each frame are adjacent.
+    Interleaved,
+    /// All samples of the first channel come first, then those of the next.
+    Planar,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PeakMode {
+    /// Maximum absolute sample value.
+    #[default]
+    Sample,
+    /// Maximum of the 4x oversampled signal as specified by ITU-R BS.1770-4.
+    True,
+}
+
 pub struct Analyzer {
     filter: PreFilter,
+    true_peak: Option<TruePeak>,
+    sampling_rate: u32,
+    channels: usize,
     peak: f64,
+
+    // Statistics of the segments before the last format change.
+    segments: [Stats; 2],
+    segment_true_peak: Option<f64>,
+
+    // Planar frames are interleaved into this buffer.
+    scratch: Vec<f64>,
 }
 
 impl Analyzer {
-    pub fn new(sampling_rate: u32, channels: usize) -> Self {
-        let mut filter = PreFilter::new(sampling_rate, channels);
+    pub fn new(sampling_rate: u32, layout: &[Channel], mode: PeakMode) -> Self {
+        let channels = layout.len();
+        let true_peak = match mode {
+            PeakMode::Sample => None,
+            PeakMode::True => Some(TruePeak::new(channels)),
+        };
+
+        Self {
+            filter: Self::filter(sampling_rate, layout),
+            true_peak,
+            sampling_rate,
+            channels,
+            peak: 0.0,
+            segments: [Stats::new(), Stats::new()],
+            segment_true_peak: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    fn filter(sampling_rate: u32, layout: &[Channel]) -> PreFilter {
+        let mut filter = PreFilter::new(sampling_rate, layout);
         filter.add_block(0.4, 4);
+        filter.add_block(3.0, 30);
+        filter
+    }
+
+    pub fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Switches to a new format for the following samples. The filters are
+    /// re-initialized for it and the statistics gathered so far are merged
+    /// with those of the new segment, so the gating blocks spanning the
+    /// change are lost but the loudness of either side is measured correctly.
+    pub fn set_format(&mut self, sampling_rate: u32, layout: &[Channel]) {
+        let channels = layout.len();
+        let filter = mem::replace(&mut self.filter, Self::filter(sampling_rate, layout));
+        merge_segment(&mut self.segments, filter);
+
+        if let Some(ref mut true_peak) = self.true_peak {
+            let peak = mem::replace(true_peak, TruePeak::new(channels)).flush();
+            self.segment_true_peak = Some(self.segment_true_peak.unwrap_or(0.0).max(peak));
+        }
+
+        self.sampling_rate = sampling_rate;
+        self.channels = channels;
+    }
 
-        Self { filter, peak: 0.0 }
+    /// Adds a block of frames in the current format. A trailing partial
+    /// frame is ignored.
+    pub fn add_frames(&mut self, samples: &[f64], layout: FrameLayout) {
+        let channels = self.channels;
+        let samples = &samples[..samples.len() - samples.len() % channels];
+        match layout {
+            FrameLayout::Interleaved => {
+                self.filter.add_frames(samples);
+                for frame in samples.chunks_exact(channels) {
+                    self.add_peak(frame);
+                }
+            }
+            FrameLayout::Planar => {
+                let frames = samples.len() / channels;
+                let mut interleaved = mem::take(&mut self.scratch);
+                interleaved.clear();
+                interleaved.extend(
+                    (0..samples.len()).map(|i| samples[i % channels * frames + i / channels]),
+                );
+                self.add_frames(&interleaved, FrameLayout::Interleaved);
+                self.scratch = interleaved;
+            }
+        }
+    }
+
+    fn add_peak(&mut self, sample: &[f64]) {
+        if let Some(ref mut true_peak) = self.true_peak {
+            true_peak.add_sample(sample);
+        }
+
+        self.peak = sample.iter().fold(self.peak, |a, f| a.max(f.abs()));
     }
 
-    pub fn add_sample(&mut self, sample: &[f64]) {
-        self.filter.add_sample(sample);
-        self.peak = sample
-            .iter()
-            .map(|f| f.abs())
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .map(|f| f.max(self.peak))
-            .unwrap();
+    pub fn flush(mut self) -> Analysis {
+        merge_segment(&mut self.segments, self.filter);
+
+        let [stats, short_term] = self.segments;
+        Analysis {
+            stats,
+            short_term,
+            peak: self.peak,
+            true_peak: self
+                .true_peak
+                .map(|p| p.flush().max(self.segment_true_peak.unwrap_or(0.0))),
+        }
     }
+}
 
-    pub fn flush(self) -> (Stats, f64) {
-        (self.filter.flush().pop().unwrap(), self.peak)
+/// Adds the statistics of a segment to those of the segments before it.
+fn merge_segment(segments: &mut [Stats; 2], filter: PreFilter) {
+    for (stats, segment) in segments.iter_mut().zip(filter.flush()) {
+        stats.merge(&segment);
     }
 }
 
 pub struct Aggregator {
     pub stats: Stats,
+    pub short_term: Stats,
     pub peak: f64,
+    pub true_peak: Option<f64>,
 }
 
 impl Aggregator {
-    pub fn aggregate(&mut self, stats: &Stats, peak: f64) {
-        self.stats.merge(stats);
-        self.peak = self.peak.max(peak);
+    pub fn aggregate(&mut self, analysis: &Analysis) {
+        self.stats.merge(&analysis.stats);
+        self.short_term.merge(&analysis.short_term);
+        self.peak = self.peak.max(analysis.peak);
+        self.true_peak = match (self.true_peak, analysis.true_peak) {
+            (Some(l), Some(r)) => Some(l.max(r)),
+            (l, r) => l.or(r),
+        };
+    }
+
+    pub fn peak(&self, mode: PeakMode) -> f64 {
+        match mode {
+            PeakMode::Sample => self.peak,
+            PeakMode::True => self.true_peak.unwrap_or(self.peak),
+        }
     }
 }
 
@@ -171,7 +611,9 @@ impl Default for Aggregator {
     fn default() -> Self {
         Self {
             stats: Stats::new(),
+            short_term: Stats::new(),
             peak: 0.0,
+            true_peak: None,
         }
     }
 }
@@ -182,10 +624,261 @@ mod tests {
 
     #[test]
     fn mp3_file() {
-        let file = Mp3File::open("test_data/sample.mp3").unwrap();
+        let file = Id3File::open("test_data/sample.mp3").unwrap();
         assert_eq!(file.artist(), Some("Artist"));
         assert_eq!(file.album(), Some("Album"));
-        assert_eq!(file.compilation(), true);
+        assert_eq!(file.album_artist(), None);
+        assert_eq!(file.musicbrainz_album_id(), None);
+        assert_eq!(file.disc(), None);
+        assert!(file.compilation());
+        assert_eq!(file.normalization(), None);
+    }
+
+    #[test]
+    fn mp3_normalization() {
+        let mut file = Id3File::open("test_data/sample.mp3").unwrap();
+        file.set_normalization(" 00000001");
+        file.set_normalization(" 00000002");
+        assert_eq!(file.normalization(), Some(" 00000002"));
+    }
+
+    #[test]
+    fn mp3_replaygain() {
+        let mut file = Id3File::open("test_data/sample.mp3").unwrap();
+        let val = ReplayGain {
+            track_gain: -6.544,
+            track_peak: 0.9876543,
+            album_gain: 1.0,
+            album_peak: 1.0,
+            reference: -18.0,
+        };
+        file.set_replaygain(&val);
+        file.set_replaygain(&val);
+
+        let texts = file
+            .tag
+            .extended_texts()
+            .map(|t| (t.description.as_str(), t.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [
+                ("REPLAYGAIN_TRACK_GAIN", "-6.54 dB"),
+                ("REPLAYGAIN_TRACK_PEAK", "0.987654"),
+                ("REPLAYGAIN_ALBUM_GAIN", "1.00 dB"),
+                ("REPLAYGAIN_ALBUM_PEAK", "1.000000"),
+            ]
+        );
+    }
+
+    #[test]
+    fn mp3_replaygain_other_case() {
+        use id3::TagLike;
+        let mut file = Id3File::open("test_data/sample.mp3").unwrap();
+        file.tag.add_frame(id3::frame::ExtendedText {
+            description: "replaygain_track_gain".to_string(),
+            value: "+3.00 dB".to_string(),
+        });
+        file.set_replaygain(&ReplayGain {
+            track_gain: -1.0,
+            track_peak: 0.5,
+            album_gain: -1.0,
+            album_peak: 0.5,
+            reference: -18.0,
+        });
+
+        let gains = file
+            .tag
+            .extended_texts()
+            .filter(|t| t.description.eq_ignore_ascii_case(ReplayGain::TRACK_GAIN))
+            .map(|t| (t.description.as_str(), t.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(gains, [("REPLAYGAIN_TRACK_GAIN", "-1.00 dB")]);
+    }
+
+    #[test]
+    fn replaygain_reference() {
+        // Gains computed for EBU R128 are written relative to -18 LUFS.
+        let val = ReplayGain {
+            track_gain: -2.0,
+            track_peak: 0.5,
+            album_gain: 0.0,
+            album_peak: 0.5,
+            reference: -23.0,
+        };
+        let tags = val.tags();
+        assert_eq!(tags[0], (ReplayGain::TRACK_GAIN, "3.00 dB".to_string()));
+        assert_eq!(tags[2], (ReplayGain::ALBUM_GAIN, "5.00 dB".to_string()));
+    }
+
+    #[test]
+    fn mp3_strip() {
+        let mut file = Id3File::open("test_data/sample.mp3").unwrap();
+        file.set_normalization(" 00000001");
+        file.set_replaygain(&ReplayGain {
+            track_gain: 0.0,
+            track_peak: 0.0,
+            album_gain: 0.0,
+            album_peak: 0.0,
+            reference: -18.0,
+        });
+
+        file.remove_normalization();
+        file.remove_replaygain();
+        assert_eq!(file.normalization(), None);
+        assert_eq!(file.tag.extended_texts().count(), 0);
+    }
+
+    #[test]
+    fn m4a_strip() {
+        let mut file = M4aFile::open("test_data/sample.m4a").unwrap();
+        file.set_normalization(" 00000001");
+        file.set_replaygain(&ReplayGain {
+            track_gain: 0.0,
+            track_peak: 0.0,
+            album_gain: 0.0,
+            album_peak: 0.0,
+            reference: -18.0,
+        });
+
+        file.remove_normalization();
+        file.remove_replaygain();
+        assert_eq!(file.normalization(), None);
+        let ident = mp4ameta::FreeformIdent::new("com.apple.iTunes", "replaygain_track_gain");
+        assert_eq!(file.tag.strings_of(&ident).next(), None);
+    }
+
+    #[test]
+    fn m4a_replaygain() {
+        let mut file = M4aFile::open("test_data/sample.m4a").unwrap();
+        let val = ReplayGain {
+            track_gain: 2.5,
+            track_peak: 0.5,
+            album_gain: -0.126,
+            album_peak: 0.75,
+            reference: -18.0,
+        };
+        file.set_replaygain(&val);
+        file.set_replaygain(&val);
+
+        let ident = mp4ameta::FreeformIdent::new("com.apple.iTunes", "replaygain_album_gain");
+        assert_eq!(
+            file.tag.strings_of(&ident).collect::<Vec<_>>(),
+            ["-0.13 dB"]
+        );
+    }
+
+    #[test]
+    fn m4a_replaygain_other_case() {
+        let mut file = M4aFile::open("test_data/sample.m4a").unwrap();
+        let upper = mp4ameta::FreeformIdent::new("com.apple.iTunes", ReplayGain::TRACK_GAIN);
+        file.tag
+            .set_data(upper.clone(), mp4ameta::Data::Utf8("+3.00 dB".to_string()));
+        file.set_replaygain(&ReplayGain {
+            track_gain: -1.0,
+            track_peak: 0.5,
+            album_gain: -1.0,
+            album_peak: 0.5,
+            reference: -18.0,
+        });
+
+        let lower = mp4ameta::FreeformIdent::new("com.apple.iTunes", "replaygain_track_gain");
+        assert_eq!(file.tag.strings_of(&upper).next(), None);
+        assert_eq!(
+            file.tag.strings_of(&lower).collect::<Vec<_>>(),
+            ["-1.00 dB"]
+        );
+    }
+
+    fn sine(analyzer: &mut Analyzer, level: f64, seconds: usize) {
+        let amplitude = 10.0_f64.powf(level / 20.0);
+        for i in 0..48000 * seconds {
+            let x = amplitude * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / 48000.0).sin();
+            analyzer.add_frames(&[x, x], FrameLayout::Interleaved);
+        }
+    }
+
+    #[test]
+    fn loudness() {
+        // EBU Tech 3341, test case 1.
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample);
+        sine(&mut analyzer, -23.0, 20);
+        let analysis = analyzer.flush();
+        let loudness = f64::from(analysis.stats.get_mean(-10.0));
+        assert!((loudness + 23.0).abs() < 0.1, "{loudness}");
+    }
+
+    #[test]
+    fn loudness_range() {
+        // EBU Tech 3342, test case 1.
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample);
+        sine(&mut analyzer, -20.0, 20);
+        sine(&mut analyzer, -30.0, 20);
+        let analysis = analyzer.flush();
+        let range = f64::from(analysis.short_term.get_loudness_range());
+        assert!((range - 10.0).abs() < 1.0, "{range}");
+    }
+
+    #[test]
+    fn loudness_range_short() {
+        // 3.4 s give only five short-term blocks.
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample);
+        for i in 0..48000 * 34 / 10 {
+            let x = 0.1 * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / 48000.0).sin();
+            analyzer.add_frames(&[x, x], FrameLayout::Interleaved);
+        }
+        let analysis = analyzer.flush();
+        let range = f64::from(analysis.short_term.get_loudness_range());
+        assert!((0.0..0.1).contains(&range), "{range}");
+    }
+
+    #[test]
+    fn true_peak() {
+        // EBU Tech 3341, test case 15: -6 dBFS sine at fs/4 with a 45 degree phase offset,
+        // expected at -6.02 dBTP +0.2/-0.4 dB.
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        for i in 0..48000 {
+            let x = (std::f64::consts::FRAC_PI_2 * i as f64 + std::f64::consts::FRAC_PI_4).sin();
+            let x = x * 0.5;
+            analyzer.add_frames(&[x, x], FrameLayout::Interleaved);
+        }
+        let analysis = analyzer.flush();
+        let sample_peak = bs1770::to_db(analysis.peak(PeakMode::Sample));
+        let true_peak = bs1770::to_db(analysis.peak(PeakMode::True));
+        assert!((sample_peak + 9.03).abs() < 0.01, "{sample_peak}");
+        assert!(-6.42 < true_peak && true_peak < -5.82, "{true_peak}");
+    }
+
+    #[test]
+    fn id3_chunk() {
+        for path in ["test_data/sample.wav", "test_data/sample.aiff"] {
+            let file = Id3File::open(path).unwrap();
+            assert_eq!(file.artist(), Some("Artist"), "{path}");
+            assert_eq!(file.album(), Some("Album"), "{path}");
+            assert!(file.compilation(), "{path}");
+            assert_eq!(file.normalization(), None, "{path}");
+        }
+
+        let file = Id3File::open("test_data/sample.caf").unwrap();
+        assert!(!file.writable());
+        assert_eq!(file.artist(), None);
+        assert!(file.save().is_err());
+    }
+
+    #[test]
+    fn wav_save() {
+        let path = std::env::temp_dir().join(format!("chksound-{}-save.wav", std::process::id()));
+        std::fs::copy("test_data/sample.wav", &path).unwrap();
+
+        let mut file = Id3File::open(&path).unwrap();
+        file.set_normalization(" 00000001");
+        file.save().unwrap();
+
+        let file = Id3File::open(&path).unwrap();
+        assert_eq!(file.normalization(), Some(" 00000001"));
+        assert_eq!(file.artist(), Some("Artist"));
+
+        std::fs::remove_file(path).unwrap();
     }
 
     #[test]
@@ -193,20 +886,123 @@ mod tests {
         let file = M4aFile::open("test_data/sample.m4a").unwrap();
         assert_eq!(file.artist(), Some("Artist"));
         assert_eq!(file.album(), Some("Album"));
-        assert_eq!(file.compilation(), true);
+        assert_eq!(file.album_artist(), None);
+        assert_eq!(file.musicbrainz_album_id(), None);
+        assert_eq!(file.disc(), None);
+        assert!(file.compilation());
+        assert_eq!(file.normalization(), None);
+    }
+
+    fn add_sine(analyzer: &mut Analyzer, amplitude: f64, seconds: u32) {
+        let rate = analyzer.sampling_rate();
+        for i in 0..rate * seconds {
+            let x =
+                amplitude * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / rate as f64).sin();
+            let frame = vec![x; analyzer.channels()];
+            analyzer.add_frames(&frame, FrameLayout::Interleaved);
+        }
+    }
+
+    #[test]
+    fn format_change() {
+        let mut reference = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        add_sine(&mut reference, 0.25, 10);
+        let reference = reference.flush();
+
+        // A stereo segment followed by a louder mono one at another rate.
+        // The mono channel carries the power of both stereo channels.
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        add_sine(&mut analyzer, 0.25, 5);
+        analyzer.set_format(44100, &Channel::default_layout(1));
+        add_sine(&mut analyzer, 0.25 * 2f64.sqrt() * 1.01, 5);
+        let analysis = analyzer.flush();
+
+        let expected = f64::from(reference.stats.get_mean(-10.0));
+        let loudness = f64::from(analysis.stats.get_mean(-10.0));
+        assert!(
+            (loudness - expected).abs() < 0.1,
+            "{loudness} vs {expected}"
+        );
+        assert!(analysis.true_peak.unwrap() > 0.35);
+    }
+
+    #[test]
+    fn channel_weights() {
+        // 5.1 in WAVE order: FL, FR, FC, LFE, BL, BR.
+        let layout = Channel::default_layout(6);
+        let loudness = |channel: usize| {
+            let mut samples = vec![0.0; 48000 * 5 * 6];
+            for (i, frame) in samples.chunks_exact_mut(6).enumerate() {
+                frame[channel] = 0.25 * (i as f64 * 0.1).sin();
+            }
+            let mut analyzer = Analyzer::new(48000, &layout, PeakMode::Sample);
+            analyzer.add_frames(&samples, FrameLayout::Interleaved);
+            f64::from(analyzer.flush().stats.get_mean(-10.0))
+        };
+
+        let front = loudness(0);
+        for channel in [1, 2] {
+            assert!((loudness(channel) - front).abs() < 0.01);
+        }
+        assert_eq!(loudness(3), f64::from(bs1770::Loudness::MIN));
+        for channel in [4, 5] {
+            let surround = loudness(channel) - front;
+            assert!((surround - 1.5).abs() < 0.01, "{surround}");
+        }
+    }
+
+    #[test]
+    fn frame_layouts() {
+        let samples = (0..48000 * 5)
+            .map(|i| (i as f64 * 0.05).sin() * (1.0 + (i % 3) as f64) / 4.0)
+            .collect::<Vec<_>>();
+
+        let mut by_sample = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        for frame in samples.chunks_exact(2) {
+            by_sample.add_frames(frame, FrameLayout::Interleaved);
+        }
+        let mut interleaved = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        for block in samples.chunks(1000) {
+            interleaved.add_frames(block, FrameLayout::Interleaved);
+        }
+        let mut by_channel = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        for block in samples.chunks(3000) {
+            let planar = [&block[0..], &block[1..]]
+                .map(|s| s.iter().step_by(2).copied().collect::<Vec<_>>())
+                .concat();
+            by_channel.add_frames(&planar, FrameLayout::Planar);
+        }
+
+        let expected = by_sample.flush();
+        for analysis in [interleaved.flush(), by_channel.flush()] {
+            assert_eq!(
+                analysis.stats.get_mean(-10.0),
+                expected.stats.get_mean(-10.0)
+            );
+            assert_eq!(
+                analysis.short_term.get_loudness_range(),
+                expected.short_term.get_loudness_range()
+            );
+            assert_eq!(analysis.peak, expected.peak);
+            assert_eq!(analysis.true_peak, expected.true_peak);
+        }
     }
 }
 
 cfg_if::cfg_if! {
     if #[cfg(target_os = "macos")] {
         mod macos;
-        pub use self::macos::*;
+        use self::macos::backends;
     } else if #[cfg(target_os = "windows")] {
         mod windows;
-        pub use self::windows::*;
+        use self::windows::backends;
     } else if #[cfg(unix)] {
+        #[cfg(feature = "opus")]
+        mod libopus;
+        #[cfg(feature = "mpg123")]
+        mod mpg123;
         mod unix;
-        pub use self::unix::*;
+        use self::unix::backends;
     } else {
         compile_error!("Unsupported target OS");
     }
@@ -0,0 +1,590 @@
+use super::decoder::{Decoder, Source};
+use super::pcm::Encoding;
+use anyhow::{bail, Result};
+use libc::c_int;
+use once_cell::sync::OnceCell as SyncOnceCell;
+
+static MPG123: SyncOnceCell<()> = SyncOnceCell::new();
+
+/// Output encodings in order of preference. The decoder works in floating
+/// point, so float output is exact; the others are fallbacks for builds of
+/// libmpg123 without it.
+const PREFERRED_ENCODINGS: [c_int; 4] = [
+    ffi::ENC_FLOAT_32,
+    ffi::ENC_SIGNED_32,
+    ffi::ENC_SIGNED_24,
+    ffi::ENC_SIGNED_16,
+];
+
+pub struct Mpg123Reader {
+    handle: ffi::Handle,
+    sampling_rate: u32,
+    channels: usize,
+    encoding: Encoding,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Mpg123Reader {
+    /// Opens a stream, making libmpg123 deliver `encoding`.
+    fn with_encoding(reader: Box<dyn Source>, encoding: c_int) -> Result<Self> {
+        MPG123.get_or_try_init(ffi::init)?;
+
+        let mut handle = ffi::Handle::new()?;
+        handle.set_format(encoding)?;
+        handle.open(reader)?;
+
+        let mut reader = Self {
+            handle,
+            sampling_rate: 0,
+            channels: 0,
+            encoding: to_encoding(encoding)?,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        reader.update_format()?;
+        reader.fill()?;
+        Ok(reader)
+    }
+
+    /// Decodes up to the next frame of audio, leaving the buffer empty at the
+    /// end of the stream. Format changes on the way are picked up here, so
+    /// the format always describes the buffered samples.
+    fn fill(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.position = 0;
+        loop {
+            match self.handle.decode_frame() {
+                Ok(ffi::Frame::Audio(buffer)) if !buffer.is_empty() => {
+                    self.buffer.extend_from_slice(buffer);
+                    return Ok(());
+                }
+                Ok(ffi::Frame::Audio(_)) => {}
+                Ok(ffi::Frame::NewFormat) => self.update_format()?,
+                Ok(ffi::Frame::Done) => return Ok(()),
+                Err(e) => bail!(e),
+            }
+        }
+    }
+
+    /// Picks up the current output format. Streams spliced from different
+    /// sources may change the sampling rate or the channel count mid-way;
+    /// the encoding stays the negotiated one.
+    fn update_format(&mut self) -> Result<()> {
+        let (sampling_rate, channels, delivered) = self.handle.format()?;
+        if to_encoding(delivered)? != self.encoding {
+            bail!(
+                "requested output encoding {:?}, got {delivered:#x}",
+                self.encoding
+            );
+        }
+        if channels <= 0 {
+            bail!("invalid channel count {channels}");
+        }
+
+        self.sampling_rate = sampling_rate as _;
+        self.channels = channels as _;
+        Ok(())
+    }
+}
+
+/// Returns how samples of an mpg123 output encoding are converted.
+fn to_encoding(encoding: c_int) -> Result<Encoding> {
+    Ok(match encoding {
+        ffi::ENC_UNSIGNED_8 => Encoding::U8,
+        ffi::ENC_SIGNED_8 => Encoding::I8,
+        ffi::ENC_SIGNED_16 => Encoding::I16,
+        ffi::ENC_SIGNED_24 => Encoding::I24,
+        ffi::ENC_SIGNED_32 => Encoding::I32,
+        ffi::ENC_FLOAT_32 => Encoding::F32,
+        ffi::ENC_FLOAT_64 => Encoding::F64,
+        _ => bail!("unsupported output encoding {encoding:#x}"),
+    })
+}
+
+impl Decoder for Mpg123Reader {
+    fn from_reader(reader: Box<dyn Source>) -> Result<Self> {
+        MPG123.get_or_try_init(ffi::init)?;
+
+        let supported = ffi::encodings();
+        match PREFERRED_ENCODINGS.iter().find(|e| supported.contains(e)) {
+            Some(encoding) => Self::with_encoding(reader, *encoding),
+            None => bail!("libmpg123 supports none of the output encodings"),
+        }
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        let (sampling_rate, channels) = (self.sampling_rate, self.channels);
+        let width = self.encoding.width();
+        let frame_size = channels * width;
+
+        let mut frames = 0;
+        let capacity = buffer.len() / channels;
+        while frames < capacity && frame_size <= self.buffer.len() - self.position {
+            let n = ((self.buffer.len() - self.position) / frame_size).min(capacity - frames);
+            let bytes = &self.buffer[self.position..self.position + n * frame_size];
+            let out = &mut buffer[frames * channels..(frames + n) * channels];
+            for (sample, b) in out.iter_mut().zip(bytes.chunks_exact(width)) {
+                // Samples are in native byte order.
+                *sample = self.encoding.decode(b, cfg!(target_endian = "big"));
+            }
+            self.position += n * frame_size;
+            frames += n;
+
+            if self.buffer.len() - self.position < frame_size {
+                self.fill()?;
+                // Frames in the new format are left to the next call.
+                if (self.sampling_rate, self.channels) != (sampling_rate, channels) {
+                    break;
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::decoder::ReadFrame;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom};
+    use std::path::Path;
+
+    #[test]
+    fn test_mp3() {
+        let r = Mpg123Reader::open("test_data/sample.mp3").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+        assert_eq!(r.encoding, Encoding::F32);
+    }
+
+    #[test]
+    fn from_memory() {
+        let data = fs::read("test_data/sample.mp3").unwrap();
+        let r = Mpg123Reader::from_reader(Box::new(Cursor::new(data))).unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+    }
+
+    #[test]
+    fn non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut name = format!("chksound-{}-", std::process::id()).into_bytes();
+        name.extend_from_slice(b"\xFF.mp3");
+        let path = std::env::temp_dir().join(OsStr::from_bytes(&name));
+        fs::copy("test_data/sample.mp3", &path).unwrap();
+
+        let r = Mpg123Reader::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(r.unwrap().channels(), 2);
+    }
+
+    #[test]
+    fn read_error() {
+        struct Broken;
+
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken stream"))
+            }
+        }
+
+        impl Seek for Broken {
+            fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+                Ok(0)
+            }
+        }
+
+        let e = Mpg123Reader::from_reader(Box::new(Broken)).err().unwrap();
+        assert_eq!(e.to_string(), "broken stream");
+    }
+
+    #[test]
+    fn integer_output() {
+        let open = |encoding| {
+            let file = File::open(Path::new("test_data/sample.mp3")).unwrap();
+            Mpg123Reader::with_encoding(Box::new(file), encoding).unwrap()
+        };
+        let mut float = open(ffi::ENC_FLOAT_32);
+        let mut int = open(ffi::ENC_SIGNED_16);
+        assert_eq!(int.encoding, Encoding::I16);
+
+        let mut frames = 0;
+        while let Some(expected) = float.read().unwrap() {
+            let sample = int.read().unwrap().unwrap();
+            for (s, e) in sample.iter().zip(&expected) {
+                // Rounded to 16 bits, give or take the last bit of the
+                // optimized synthesis, or clipped beyond full scale.
+                assert!((s - e.clamp(-1.0, 32767.0 / 32768.0)).abs() <= 2.0 / 32768.0);
+            }
+            frames += 1;
+        }
+        assert_eq!(int.read().unwrap(), None);
+        assert!(frames > 0);
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(to_encoding(ffi::ENC_SIGNED_16).unwrap(), Encoding::I16);
+        assert_eq!(to_encoding(ffi::ENC_SIGNED_24).unwrap(), Encoding::I24);
+        assert_eq!(to_encoding(ffi::ENC_FLOAT_64).unwrap(), Encoding::F64);
+        // Unsigned 16 bits.
+        assert!(to_encoding(0x60).is_err());
+    }
+
+    /// An MPEG Layer I stream of a 1 kHz sine, one second at -20 dBFS in
+    /// stereo at 48 kHz followed by one second 3 dB louder in mono at 32 kHz,
+    /// so that both halves measure -20 LUFS.
+    #[test]
+    fn format_change() {
+        use crate::audio::{Analyzer, PeakMode};
+
+        let mut r = Mpg123Reader::open("test_data/format_change.mp1").unwrap();
+        assert_eq!((r.sampling_rate(), r.channels()), (48000, 2));
+
+        let mut analyzer = Analyzer::new(r.sampling_rate(), &r.channel_layout(), PeakMode::Sample);
+        let mut buffer = Vec::new();
+        let mut frames = Vec::new();
+        loop {
+            let (sampling_rate, channels) = (r.sampling_rate(), r.channels());
+            if (sampling_rate, channels) != (analyzer.sampling_rate(), analyzer.channels()) {
+                analyzer.set_format(sampling_rate, &r.channel_layout());
+            }
+            buffer.resize(4096 * channels, 0.0);
+            let n = r.read_frames(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            analyzer.add_frames(&buffer[..n * channels], r.frame_layout());
+            match frames.last_mut() {
+                Some((format, count)) if *format == (sampling_rate, channels) => *count += n,
+                _ => frames.push(((sampling_rate, channels), n)),
+            }
+        }
+        assert_eq!(frames, [((48000, 2), 125 * 384), ((32000, 1), 84 * 384)]);
+
+        let analysis = analyzer.flush();
+        let loudness = f64::from(analysis.stats.get_mean(-10.0));
+        assert!((loudness + 20.0).abs() < 0.1, "{loudness}");
+        assert!(
+            (analysis.peak - 0.1 * 2f64.sqrt()).abs() < 0.005,
+            "{}",
+            analysis.peak
+        );
+    }
+
+    /// The pure-Rust decoder must measure the same loudness within 0.01 LU
+    /// and the same sample peak within 0.01 dB.
+    #[cfg(feature = "symphonia-mp3")]
+    #[test]
+    fn symphonia_mp3() {
+        use crate::audio::{bs1770, Analyzer, FrameLayout, PeakMode, Registry};
+
+        let analyze = |name| {
+            let registry = Registry::default().with_backend(name).unwrap();
+            let mut r = registry.open(Path::new("test_data/sample.mp3")).unwrap();
+            let mut analyzer =
+                Analyzer::new(r.sampling_rate(), &r.channel_layout(), PeakMode::Sample);
+            while let Some(sample) = r.read().unwrap() {
+                analyzer.add_frames(&sample, FrameLayout::Interleaved);
+            }
+            let analysis = analyzer.flush();
+            (
+                f64::from(analysis.stats.get_mean(-10.0)),
+                bs1770::to_db(analysis.peak),
+            )
+        };
+
+        let (mpg123_loudness, mpg123_peak) = analyze("mpg123");
+        let (loudness, peak) = analyze("symphonia");
+        assert!((loudness - mpg123_loudness).abs() < 0.01);
+        assert!((peak - mpg123_peak).abs() < 0.01);
+    }
+}
+
+mod ffi {
+    #![allow(non_camel_case_types)]
+
+    use crate::audio::decoder::Source;
+    use libc::{c_char, c_int, c_long, c_uchar, c_void, off_t, ssize_t};
+    use std::ffi::CStr;
+    use std::fmt;
+    use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+    use std::ptr::{null, null_mut};
+    use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+    #[repr(C)]
+    struct mpg123_handle_struct(c_void);
+    type mpg123_handle = *mut mpg123_handle_struct;
+
+    const MPG123_DONE: i32 = -12;
+    const MPG123_NEW_FORMAT: i32 = -11;
+    const MPG123_OK: i32 = 0;
+
+    const MPG123_MONO: c_int = 1;
+    const MPG123_STEREO: c_int = 2;
+
+    pub const ENC_UNSIGNED_8: c_int = 0x01;
+    pub const ENC_SIGNED_8: c_int = 0x82;
+    pub const ENC_SIGNED_16: c_int = 0xD0;
+    pub const ENC_SIGNED_24: c_int = 0x5080;
+    pub const ENC_SIGNED_32: c_int = 0x1180;
+    pub const ENC_FLOAT_32: c_int = 0x200;
+    pub const ENC_FLOAT_64: c_int = 0x400;
+
+    #[link(name = "mpg123")]
+    extern "C" {
+        fn mpg123_init() -> c_int;
+
+        fn mpg123_new(decoder: *const c_char, error: *mut c_int) -> mpg123_handle;
+        fn mpg123_delete(mh: mpg123_handle);
+
+        fn mpg123_plain_strerror(errcode: c_int) -> *const c_char;
+
+        fn mpg123_rates(list: *mut *const c_long, number: *mut usize);
+        fn mpg123_encodings(list: *mut *const c_int, number: *mut usize);
+
+        fn mpg123_format_none(mh: mpg123_handle) -> c_int;
+        fn mpg123_format(
+            mh: mpg123_handle,
+            rate: c_long,
+            channels: c_int,
+            encodings: c_int,
+        ) -> c_int;
+
+        fn mpg123_getformat(
+            mh: mpg123_handle,
+            rate: *mut c_long,
+            channels: *mut c_int,
+            encoding: *mut c_int,
+        ) -> c_int;
+
+        fn mpg123_replace_reader_handle(
+            mh: mpg123_handle,
+            r_read: Option<unsafe extern "C" fn(*mut c_void, *mut c_void, usize) -> ssize_t>,
+            r_lseek: Option<unsafe extern "C" fn(*mut c_void, off_t, c_int) -> off_t>,
+            cleanup: Option<unsafe extern "C" fn(*mut c_void)>,
+        ) -> c_int;
+
+        fn mpg123_open_handle(mh: mpg123_handle, iohandle: *mut c_void) -> c_int;
+
+        fn mpg123_decode_frame(
+            mh: mpg123_handle,
+            num: *mut off_t,
+            audio: *mut *mut c_uchar,
+            bytes: *mut usize,
+        ) -> c_int;
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        Mpg123(c_int),
+        /// The stream failed while libmpg123 was reading it.
+        Io(io::Error),
+    }
+
+    impl From<c_int> for Error {
+        fn from(error: c_int) -> Self {
+            Self::Mpg123(error)
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Mpg123(error) => {
+                    let msg = unsafe { CStr::from_ptr(mpg123_plain_strerror(*error)) };
+                    write!(f, "{}", msg.to_string_lossy())
+                }
+                Self::Io(e) => e.fmt(f),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+    pub fn init() -> Result<()> {
+        match unsafe { mpg123_init() } {
+            MPG123_OK => Ok(()),
+            error => Err(error.into()),
+        }
+    }
+
+    /// Returns the output encodings the library was built with.
+    pub fn encodings() -> &'static [c_int] {
+        let mut list = null();
+        let mut number = 0;
+        unsafe {
+            mpg123_encodings(&mut list, &mut number);
+            from_raw_parts(list, number)
+        }
+    }
+
+    fn rates() -> &'static [c_long] {
+        let mut list = null();
+        let mut number = 0;
+        unsafe {
+            mpg123_rates(&mut list, &mut number);
+            from_raw_parts(list, number)
+        }
+    }
+
+    /// The stream libmpg123 reads through the callbacks below.
+    struct Stream {
+        reader: Box<dyn Source>,
+        /// The last I/O error, which libmpg123 only sees as a failed read.
+        error: Option<io::Error>,
+    }
+
+    unsafe extern "C" fn read(handle: *mut c_void, buf: *mut c_void, count: usize) -> ssize_t {
+        let stream = &mut *(handle as *mut Stream);
+        let buf = from_raw_parts_mut(buf as *mut u8, count);
+        loop {
+            match stream.reader.read(buf) {
+                Ok(n) => return n as ssize_t,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    stream.error = Some(e);
+                    return -1;
+                }
+            }
+        }
+    }
+
+    unsafe extern "C" fn lseek(handle: *mut c_void, offset: off_t, whence: c_int) -> off_t {
+        let stream = &mut *(handle as *mut Stream);
+        let pos = match whence {
+            libc::SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            libc::SEEK_CUR => SeekFrom::Current(offset),
+            libc::SEEK_END => SeekFrom::End(offset),
+            _ => return -1,
+        };
+        match stream.reader.seek(pos) {
+            Ok(pos) => pos as off_t,
+            Err(e) => {
+                stream.error = Some(e);
+                -1
+            }
+        }
+    }
+
+    pub struct Handle {
+        handle: mpg123_handle,
+        /// Owned by the handle and freed after libmpg123 is done with it.
+        stream: *mut Stream,
+    }
+
+    impl Handle {
+        const NULL: mpg123_handle = null_mut();
+
+        pub fn new() -> Result<Self> {
+            let mut error = MPG123_OK;
+            match unsafe { mpg123_new(null(), &mut error) } {
+                Self::NULL => Err(error.into()),
+                handle => Ok(Self {
+                    handle,
+                    stream: null_mut(),
+                }),
+            }
+        }
+
+        /// Decodes from `reader` instead of a file libmpg123 opens itself.
+        pub fn open(&mut self, reader: Box<dyn Source>) -> Result<()> {
+            match unsafe {
+                mpg123_replace_reader_handle(self.handle, Some(read), Some(lseek), None)
+            } {
+                MPG123_OK => {}
+                error => return Err(error.into()),
+            }
+
+            self.stream = Box::into_raw(Box::new(Stream {
+                reader,
+                error: None,
+            }));
+            match unsafe { mpg123_open_handle(self.handle, self.stream as *mut c_void) } {
+                MPG123_OK => Ok(()),
+                error => Err(self.error(error)),
+            }
+        }
+
+        /// Returns the I/O error behind a failure, if any.
+        fn error(&mut self, error: c_int) -> Error {
+            match unsafe { self.stream.as_mut() }.and_then(|s| s.error.take()) {
+                Some(e) => Error::Io(e),
+                None => error.into(),
+            }
+        }
+
+        /// Restricts the output to `encoding` at any standard rate.
+        pub fn set_format(&self, encoding: c_int) -> Result<()> {
+            match unsafe { mpg123_format_none(self.handle) } {
+                MPG123_OK => {}
+                error => return Err(error.into()),
+            }
+            for rate in rates() {
+                match unsafe {
+                    mpg123_format(self.handle, *rate, MPG123_MONO | MPG123_STEREO, encoding)
+                } {
+                    MPG123_OK => {}
+                    error => return Err(error.into()),
+                }
+            }
+            Ok(())
+        }
+
+        pub fn format(&mut self) -> Result<(i64, i32, i32)> {
+            let mut rate = 0;
+            let mut channels = 0;
+            let mut encoding = 0;
+            match unsafe { mpg123_getformat(self.handle, &mut rate, &mut channels, &mut encoding) }
+            {
+                MPG123_OK => Ok((rate, channels, encoding)),
+                error => Err(self.error(error)),
+            }
+        }
+
+        /// Decodes the next frame into samples in the negotiated encoding.
+        pub fn decode_frame(&mut self) -> Result<Frame<'_>> {
+            let mut audio = null_mut();
+            let mut bytes = 0;
+            match unsafe { mpg123_decode_frame(self.handle, null_mut(), &mut audio, &mut bytes) } {
+                MPG123_OK => Ok(Frame::Audio(unsafe { from_raw_parts(audio, bytes) })),
+                MPG123_NEW_FORMAT => Ok(Frame::NewFormat),
+                MPG123_DONE => Ok(Frame::Done),
+                error => Err(self.error(error)),
+            }
+        }
+    }
+
+    pub enum Frame<'a> {
+        Audio(&'a [u8]),
+        /// The stream changed its sampling rate or channel count; the new
+        /// format applies to the following frames.
+        NewFormat,
+        Done,
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe {
+                mpg123_delete(self.handle);
+                if !self.stream.is_null() {
+                    drop(Box::from_raw(self.stream));
+                }
+            }
+        }
+    }
+}
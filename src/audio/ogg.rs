@@ -0,0 +1,473 @@
+use super::vorbis_comment::VorbisComment;
+use super::{AudioFile, ReplayGain};
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const CONTINUED: u8 = 0x01;
+const BOS: u8 = 0x02;
+const EOS: u8 = 0x04;
+
+// Ogg uses the unreflected CRC-32 with polynomial 0x04C11DB7 and no final XOR.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x80000000 != 0 {
+                (r << 1) ^ 0x04C11DB7
+            } else {
+                r << 1
+            };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn read(reader: &mut impl Read) -> Result<Option<Self>> {
+        let mut header = [0; 27];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if &header[..4] != b"OggS" || header[4] != 0 {
+            bail!("invalid Ogg page");
+        }
+
+        let mut segments = vec![0; header[26] as usize];
+        reader.read_exact(&mut segments)?;
+        let mut data = vec![0; segments.iter().map(|s| *s as usize).sum()];
+        reader.read_exact(&mut data)?;
+
+        Ok(Some(Self {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into()?),
+            serial: u32::from_le_bytes(header[14..18].try_into()?),
+            sequence: u32::from_le_bytes(header[18..22].try_into()?),
+            segments,
+            data,
+        }))
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(self.header_type);
+        page.extend_from_slice(&self.granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(self.segments.len() as u8);
+        page.extend_from_slice(&self.segments);
+        page.extend_from_slice(&self.data);
+
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&page)?;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        (27 + self.segments.len() + self.data.len()) as u64
+    }
+}
+
+/// Lays out header packets into pages. The first packet gets a page of its
+/// own and the rest are packed together, as the Vorbis and Opus mappings
+/// require.
+fn paginate(packets: &[&[u8]], serial: u32) -> Vec<Page> {
+    let mut pages = Vec::new();
+    for (i, group) in [&packets[..1], &packets[1..]].into_iter().enumerate() {
+        let mut lacing = Vec::new();
+        let mut data = Vec::new();
+        for packet in group {
+            lacing.extend(std::iter::repeat_n((255, false), packet.len() / 255));
+            lacing.push(((packet.len() % 255) as u8, true));
+            data.extend_from_slice(packet);
+        }
+
+        let mut data = &data[..];
+        let mut continued = false;
+        for (j, segments) in lacing.chunks(255).enumerate() {
+            let len = segments.iter().map(|(s, _)| *s as usize).sum();
+            let mut header_type = if continued { CONTINUED } else { 0 };
+            if i == 0 && j == 0 {
+                header_type |= BOS;
+            }
+
+            pages.push(Page {
+                header_type,
+                // Pages on which no packet ends have no granule position.
+                granule: if segments.iter().any(|(_, end)| *end) {
+                    0
+                } else {
+                    u64::MAX
+                },
+                serial,
+                sequence: pages.len() as u32,
+                segments: segments.iter().map(|(s, _)| *s).collect(),
+                data: data[..len].to_vec(),
+            });
+            data = &data[len..];
+            continued = !segments[segments.len() - 1].1;
+        }
+    }
+    pages
+}
+
+/// Splits the pages of the first logical stream into packets.
+pub(super) struct PacketReader<R> {
+    reader: R,
+    serial: Option<u32>,
+    packets: VecDeque<Vec<u8>>,
+    packet: Vec<u8>,
+    offset: u64,
+    sequence: u32,
+    multiplexed: bool,
+    end: Option<u64>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub(super) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            serial: None,
+            packets: VecDeque::new(),
+            packet: Vec::new(),
+            offset: 0,
+            sequence: 0,
+            multiplexed: false,
+            end: None,
+        }
+    }
+
+    pub(super) fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+            if self.end.is_some() {
+                return Ok(None);
+            }
+
+            let Some(page) = Page::read(&mut self.reader)? else {
+                return Ok(None);
+            };
+            self.offset += page.len();
+
+            // Pages of other streams are skipped, and so are chained streams
+            // following the end of the first one.
+            if page.serial != *self.serial.get_or_insert(page.serial) {
+                self.multiplexed = true;
+                continue;
+            }
+            self.sequence = page.sequence;
+
+            let mut data = &page.data[..];
+            for segment in &page.segments {
+                let len = *segment as usize;
+                self.packet.extend_from_slice(&data[..len]);
+                data = &data[len..];
+                if len < 255 {
+                    self.packets.push_back(std::mem::take(&mut self.packet));
+                }
+            }
+
+            if page.header_type & EOS != 0 {
+                self.end = Some(page.granule);
+            }
+        }
+    }
+
+    /// Granule position of the last page, once it has been read.
+    #[cfg_attr(not(feature = "opus"), allow(dead_code))]
+    pub(super) fn end(&self) -> Option<u64> {
+        self.end
+    }
+}
+
+/// The header packets of an Ogg stream and the pages they occupy.
+pub(super) struct Headers {
+    pub(super) path: PathBuf,
+    serial: u32,
+    pub(super) packets: Vec<Vec<u8>>,
+    end: u64,
+    pages: u32,
+}
+
+impl Headers {
+    pub(super) fn read(path: &Path, count: usize) -> Result<Self> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+
+        let mut packets = Vec::new();
+        while packets.len() < count {
+            match reader.next_packet()? {
+                Some(packet) => packets.push(packet),
+                None => bail!("truncated Ogg headers"),
+            }
+        }
+
+        if reader.multiplexed {
+            bail!("multiplexed Ogg streams are not supported");
+        }
+        if !reader.packets.is_empty() || !reader.packet.is_empty() {
+            bail!("audio data shares a page with the headers");
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            serial: reader.serial.unwrap(),
+            packets,
+            end: reader.offset,
+            pages: reader.sequence + 1,
+        })
+    }
+
+    /// Replaces the header packets, renumbering the following pages if the
+    /// number of header pages changes.
+    pub(super) fn write(&self, packets: &[&[u8]]) -> Result<()> {
+        let pages = paginate(packets, self.serial);
+        let offset = (pages.len() as u32).wrapping_sub(self.pages);
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.end))?;
+        super::replace_file(&self.path, |writer| {
+            for page in pages {
+                page.write(writer)?;
+            }
+
+            if offset == 0 {
+                io::copy(&mut reader, writer)?;
+            } else {
+                while let Some(mut page) = Page::read(&mut reader)? {
+                    if page.serial == self.serial {
+                        page.sequence = page.sequence.wrapping_add(offset);
+                    }
+                    page.write(writer)?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Returns the byte ranges of the page bodies following the header packets.
+/// Rewriting the headers may renumber these pages, but leaves their bodies
+/// unchanged.
+pub(super) fn audio_data(path: &Path) -> Result<Vec<Range<u64>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let count = match PacketReader::new(&mut reader).next_packet()? {
+        Some(packet) if packet.starts_with(b"OpusHead") => 2,
+        _ => 3,
+    };
+    let headers = Headers::read(path, count)?;
+
+    let mut ranges = Vec::new();
+    let mut pos = reader.seek(SeekFrom::Start(headers.end))?;
+    while let Some(page) = Page::read(&mut reader)? {
+        let end = pos + page.len();
+        ranges.push(end - page.data.len() as u64..end);
+        pos = end;
+    }
+    Ok(ranges)
+}
+
+/// An Ogg Vorbis file. Only the first logical stream is considered.
+pub struct OggFile {
+    headers: Headers,
+    comment: VorbisComment,
+}
+
+impl OggFile {
+    const COMMENT: &'static [u8] = b"\x03vorbis";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let headers = Headers::read(path.as_ref(), 3)?;
+
+        if !headers.packets[0].starts_with(b"\x01vorbis") {
+            bail!("unsupported Ogg codec");
+        }
+        let Some(comment) = headers.packets[1].strip_prefix(Self::COMMENT) else {
+            bail!("missing Vorbis comment header");
+        };
+        let comment = VorbisComment::parse(comment)?;
+
+        Ok(Self { headers, comment })
+    }
+}
+
+impl AudioFile for OggFile {
+    fn path(&self) -> &Path {
+        &self.headers.path
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut comment = Self::COMMENT.to_vec();
+        comment.extend(self.comment.to_bytes());
+        comment.push(1);
+
+        let packets = &self.headers.packets;
+        self.headers.write(&[&packets[0], &comment, &packets[2]])
+    }
+
+    fn artist(&self) -> Option<&str> {
+        self.comment.artist()
+    }
+
+    fn album(&self) -> Option<&str> {
+        self.comment.album()
+    }
+
+    fn album_artist(&self) -> Option<&str> {
+        self.comment.album_artist()
+    }
+
+    fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.comment.musicbrainz_album_id()
+    }
+
+    fn disc(&self) -> Option<u32> {
+        self.comment.disc()
+    }
+
+    fn compilation(&self) -> bool {
+        self.comment.compilation()
+    }
+
+    fn supports_normalization(&self) -> bool {
+        false
+    }
+
+    fn normalization(&self) -> Option<&str> {
+        None
+    }
+
+    fn set_normalization(&mut self, _val: &str) {}
+
+    fn set_replaygain(&mut self, val: &ReplayGain) {
+        self.comment.set_replaygain(val);
+    }
+
+    fn remove_normalization(&mut self) -> bool {
+        false
+    }
+
+    fn remove_replaygain(&mut self) -> bool {
+        self.comment.remove_replaygain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn copy(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chksound-{}-{name}", std::process::id()));
+        fs::copy("test_data/sample.ogg", &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0x89A1897F);
+    }
+
+    #[test]
+    fn ogg_file() {
+        let file = OggFile::open("test_data/sample.ogg").unwrap();
+        assert_eq!(file.artist(), Some("Artist"));
+        assert_eq!(file.album(), Some("Album"));
+        assert_eq!(file.album_artist(), None);
+        assert_eq!(file.musicbrainz_album_id(), None);
+        assert_eq!(file.disc(), None);
+        assert!(file.compilation());
+        assert_eq!(file.normalization(), None);
+    }
+
+    #[test]
+    fn ogg_save_in_place() {
+        let path = copy("same.ogg");
+
+        let mut file = OggFile::open(&path).unwrap();
+        file.comment.set("ALBUM", "Other");
+        file.save().unwrap();
+
+        let file = OggFile::open(&path).unwrap();
+        assert_eq!(file.album(), Some("Other"));
+        assert_eq!(file.headers.pages, 2);
+        assert_eq!(
+            fs::read(&path).unwrap()[file.headers.end as usize..],
+            fs::read("test_data/sample.ogg").unwrap()[file.headers.end as usize..]
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ogg_save() {
+        let path = copy("save.ogg");
+
+        let mut file = OggFile::open(&path).unwrap();
+        file.set_replaygain(&ReplayGain {
+            track_gain: -6.544,
+            track_peak: 0.9876543,
+            album_gain: 1.0,
+            album_peak: 1.0,
+            reference: -18.0,
+        });
+        // Force the comment header across several pages.
+        file.comment.set("COMMENT", "x".repeat(70000));
+        file.save().unwrap();
+
+        let file = OggFile::open(&path).unwrap();
+        assert_eq!(file.comment.get(ReplayGain::TRACK_GAIN), Some("-6.54 dB"));
+        assert_eq!(file.comment.get("COMMENT").map(str::len), Some(70000));
+        assert_eq!(file.artist(), Some("Artist"));
+        assert!(file.headers.pages > 2);
+
+        let original = OggFile::open("test_data/sample.ogg").unwrap();
+        let mut expected = BufReader::new(File::open("test_data/sample.ogg").unwrap());
+        let mut actual = BufReader::new(File::open(&path).unwrap());
+        expected
+            .seek(SeekFrom::Start(original.headers.end))
+            .unwrap();
+        actual.seek(SeekFrom::Start(file.headers.end)).unwrap();
+
+        let offset = file.headers.pages - original.headers.pages;
+        while let Some(expected) = Page::read(&mut expected).unwrap() {
+            let actual = Page::read(&mut actual).unwrap().unwrap();
+            assert_eq!(actual.sequence, expected.sequence + offset);
+            assert_eq!(actual.granule, expected.granule);
+            assert_eq!(actual.data, expected.data);
+        }
+        assert!(Page::read(&mut actual).unwrap().is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use super::ogg::Headers;
+use super::vorbis_comment::VorbisComment;
+use super::{AudioFile, ReplayGain};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Reference level of the R128 gain tags as defined by RFC 7845.
+const R128_REFERENCE: f64 = -23.0;
+
+/// An Ogg Opus file. Gains are written as R128_TRACK_GAIN and R128_ALBUM_GAIN,
+/// Q7.8 fixed point values in dB relative to -23 LUFS, on top of the output
+/// gain in the OpusHead header. When the output gain is changed, the original
+/// one is kept in a comment so that removing the gains restores it.
+pub struct OpusFile {
+    headers: Headers,
+    comment: VorbisComment,
+    output_gain: i16,
+    apply_output_gain: bool,
+}
+
+impl OpusFile {
+    const TRACK_GAIN: &'static str = "R128_TRACK_GAIN";
+    const ALBUM_GAIN: &'static str = "R128_ALBUM_GAIN";
+    const ORIGINAL_OUTPUT_GAIN: &'static str = "CHKSOUND_ORIGINAL_OUTPUT_GAIN";
+    const HEAD: &'static [u8] = b"OpusHead";
+    const TAGS: &'static [u8] = b"OpusTags";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let headers = Headers::read(path.as_ref(), 2)?;
+
+        let head = &headers.packets[0];
+        if !head.starts_with(Self::HEAD) || head.len() < 19 {
+            bail!("unsupported Ogg codec");
+        }
+        let output_gain = i16::from_le_bytes([head[16], head[17]]);
+
+        let Some(comment) = headers.packets[1].strip_prefix(Self::TAGS) else {
+            bail!("missing OpusTags header");
+        };
+        let comment = VorbisComment::parse(comment)?;
+
+        Ok(Self {
+            headers,
+            comment,
+            output_gain,
+            apply_output_gain: false,
+        })
+    }
+
+    /// Moves the album gain into the header output gain, so that players
+    /// unaware of the R128 tags play the album at the reference level.
+    pub fn with_output_gain(mut self, apply: bool) -> Self {
+        self.apply_output_gain = apply;
+        self
+    }
+
+    /// Returns the header output gain and the R128 track and album gains.
+    fn gains(&self, val: &ReplayGain) -> (i16, i16, i16) {
+        // The analysis already includes the current output gain.
+        let track = to_q78(val.track_gain - val.reference + R128_REFERENCE);
+        let album = to_q78(val.album_gain - val.reference + R128_REFERENCE);
+        if !self.apply_output_gain {
+            return (self.output_gain, track, album);
+        }
+
+        let output_gain = self.output_gain.saturating_add(album);
+        let delta = output_gain - self.output_gain;
+        (output_gain, track - delta, album - delta)
+    }
+}
+
+fn to_q78(gain: f64) -> i16 {
+    (gain * 256.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+impl AudioFile for OpusFile {
+    fn path(&self) -> &Path {
+        &self.headers.path
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut head = self.headers.packets[0].clone();
+        head[16..18].copy_from_slice(&self.output_gain.to_le_bytes());
+
+        let mut tags = Self::TAGS.to_vec();
+        tags.extend(self.comment.to_bytes());
+
+        self.headers.write(&[&head, &tags])
+    }
+
+    fn artist(&self) -> Option<&str> {
+        self.comment.artist()
+    }
+
+    fn album(&self) -> Option<&str> {
+        self.comment.album()
+    }
+
+    fn album_artist(&self) -> Option<&str> {
+        self.comment.album_artist()
+    }
+
+    fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.comment.musicbrainz_album_id()
+    }
+
+    fn disc(&self) -> Option<u32> {
+        self.comment.disc()
+    }
+
+    fn compilation(&self) -> bool {
+        self.comment.compilation()
+    }
+
+    fn supports_normalization(&self) -> bool {
+        false
+    }
+
+    fn normalization(&self) -> Option<&str> {
+        None
+    }
+
+    fn set_normalization(&mut self, _val: &str) {}
+
+    fn replaygain_tags(&self, val: &ReplayGain) -> Vec<(&'static str, String)> {
+        let (_, track, album) = self.gains(val);
+        vec![
+            (Self::TRACK_GAIN, track.to_string()),
+            (Self::ALBUM_GAIN, album.to_string()),
+        ]
+    }
+
+    fn set_replaygain(&mut self, val: &ReplayGain) {
+        let (output_gain, _, _) = self.gains(val);
+        for (name, value) in self.replaygain_tags(val) {
+            self.comment.set(name, value);
+        }
+        // Only the first change records the gain, later ones keep it.
+        if output_gain != self.output_gain && self.comment.get(Self::ORIGINAL_OUTPUT_GAIN).is_none()
+        {
+            self.comment
+                .set(Self::ORIGINAL_OUTPUT_GAIN, self.output_gain.to_string());
+        }
+        self.output_gain = output_gain;
+    }
+
+    fn remove_normalization(&mut self) -> bool {
+        false
+    }
+
+    fn remove_replaygain(&mut self) -> bool {
+        if let Some(Ok(gain)) = self
+            .comment
+            .get(Self::ORIGINAL_OUTPUT_GAIN)
+            .map(str::parse::<i16>)
+        {
+            self.output_gain = gain;
+        }
+        let original = self.comment.remove(Self::ORIGINAL_OUTPUT_GAIN);
+        let track = self.comment.remove(Self::TRACK_GAIN);
+        let album = self.comment.remove(Self::ALBUM_GAIN);
+        // RFC 7845 forbids ReplayGain tags in Opus, but some taggers add them.
+        self.comment.remove_replaygain() | original | track | album
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn replaygain(track_loudness: f64, album_loudness: f64) -> ReplayGain {
+        ReplayGain {
+            track_gain: -18.0 - track_loudness,
+            track_peak: 1.0,
+            album_gain: -18.0 - album_loudness,
+            album_peak: 1.0,
+            reference: -18.0,
+        }
+    }
+
+    #[test]
+    fn opus_file() {
+        let file = OpusFile::open("test_data/sample.opus").unwrap();
+        assert_eq!(file.artist(), Some("Artist"));
+        assert_eq!(file.album(), Some("Album"));
+        assert_eq!(file.album_artist(), None);
+        assert_eq!(file.musicbrainz_album_id(), None);
+        assert_eq!(file.disc(), None);
+        assert!(file.compilation());
+        assert_eq!(file.output_gain, 0);
+    }
+
+    #[test]
+    fn r128_gains() {
+        let file = OpusFile::open("test_data/sample.opus").unwrap();
+        let val = replaygain(-14.0, -16.5);
+        assert_eq!(file.gains(&val), (0, -9 * 256, -6 * 256 - 128));
+
+        let file = file.with_output_gain(true);
+        assert_eq!(file.gains(&val), (-6 * 256 - 128, -2 * 256 - 128, 0));
+    }
+
+    #[test]
+    fn opus_save() {
+        let path = std::env::temp_dir().join(format!("chksound-{}.opus", std::process::id()));
+        fs::copy("test_data/sample.opus", &path).unwrap();
+
+        let mut file = OpusFile::open(&path).unwrap().with_output_gain(true);
+        file.set_replaygain(&replaygain(-14.0, -16.5));
+        file.save().unwrap();
+
+        let mut file = OpusFile::open(&path).unwrap().with_output_gain(true);
+        assert_eq!(file.output_gain, -1664);
+        assert_eq!(file.comment.get(OpusFile::TRACK_GAIN), Some("-640"));
+        assert_eq!(file.comment.get(OpusFile::ALBUM_GAIN), Some("0"));
+        assert_eq!(file.comment.get(OpusFile::ORIGINAL_OUTPUT_GAIN), Some("0"));
+
+        // The analysis of a retagged file includes the new output gain.
+        file.set_replaygain(&replaygain(-14.0 - 6.5, -16.5 - 6.5 + 1.0));
+        file.save().unwrap();
+
+        let mut file = OpusFile::open(&path).unwrap();
+        assert_eq!(file.output_gain, -1664 - 256);
+        assert_eq!(file.comment.get(OpusFile::ORIGINAL_OUTPUT_GAIN), Some("0"));
+
+        assert!(file.remove_replaygain());
+        file.save().unwrap();
+
+        let file = OpusFile::open(&path).unwrap();
+        assert_eq!(file.comment.get(OpusFile::TRACK_GAIN), None);
+        assert_eq!(file.comment.get(OpusFile::ORIGINAL_OUTPUT_GAIN), None);
+        assert_eq!(file.output_gain, 0);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,462 @@
+use super::decoder::{Channel, Decoder, Source};
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Encoding {
+    U8,
+    I8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl Encoding {
+    fn wave(format: u16, bits: usize) -> Result<Self> {
+        Ok(match (format, bits) {
+            (WAVE_FORMAT_PCM, 8) => Self::U8,
+            (WAVE_FORMAT_PCM, 16) => Self::I16,
+            (WAVE_FORMAT_PCM, 24) => Self::I24,
+            (WAVE_FORMAT_PCM, 32) => Self::I32,
+            (WAVE_FORMAT_IEEE_FLOAT, 32) => Self::F32,
+            (WAVE_FORMAT_IEEE_FLOAT, 64) => Self::F64,
+            _ => bail!("unsupported format {format:#06X} with {bits} bits"),
+        })
+    }
+
+    fn aiff(compression: &[u8], bits: usize) -> Result<Self> {
+        Ok(match (compression, bits) {
+            (b"NONE" | b"twos" | b"sowt", 8) => Self::I8,
+            (b"NONE" | b"twos" | b"sowt", 16) => Self::I16,
+            (b"NONE" | b"twos" | b"sowt", 24) => Self::I24,
+            (b"NONE" | b"twos" | b"sowt", 32) => Self::I32,
+            (b"raw ", 8) => Self::U8,
+            (b"fl32" | b"FL32", 32) => Self::F32,
+            (b"fl64" | b"FL64", 64) => Self::F64,
+            _ => bail!(
+                "unsupported compression {} with {bits} bits",
+                String::from_utf8_lossy(compression)
+            ),
+        })
+    }
+
+    /// Size of a sample in bytes.
+    #[cfg_attr(not(feature = "mpg123"), allow(dead_code))]
+    pub(super) fn width(self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    pub(super) fn decode(self, b: &[u8], big_endian: bool) -> f64 {
+        let mut le = [0; 8];
+        le[..b.len()].copy_from_slice(b);
+        if big_endian {
+            le[..b.len()].reverse();
+        }
+
+        match self {
+            Self::U8 => (le[0] as f64 - 128.0) / 128.0,
+            Self::I8 => le[0] as i8 as f64 / 128.0,
+            Self::I16 => i16::from_le_bytes([le[0], le[1]]) as f64 / 32768.0,
+            Self::I24 => (i32::from_le_bytes([0, le[0], le[1], le[2]]) >> 8) as f64 / 8388608.0,
+            Self::I32 => i32::from_le_bytes([le[0], le[1], le[2], le[3]]) as f64 / 2147483648.0,
+            Self::F32 => f32::from_le_bytes([le[0], le[1], le[2], le[3]]) as f64,
+            Self::F64 => f64::from_le_bytes(le),
+        }
+    }
+}
+
+struct Format {
+    encoding: Encoding,
+    big_endian: bool,
+    sampling_rate: u32,
+    channels: usize,
+    width: usize,
+    /// WAVE channel mask, if the file has one.
+    channel_mask: Option<u32>,
+}
+
+/// Reads uncompressed RIFF, RF64/BW64 and AIFF/AIFC files.
+pub struct PcmReader {
+    reader: BufReader<Box<dyn Source>>,
+    format: Format,
+    remaining: u64,
+    buffer: Vec<u8>,
+}
+
+impl PcmReader {
+    fn parse_wave(reader: &mut (impl Read + Seek)) -> Result<(Format, (u64, u64))> {
+        let mut data_size = None;
+        let mut format = None;
+        let mut data = None;
+        loop {
+            let (id, mut size) = match read_chunk_header(reader, u32::from_le_bytes)? {
+                Some(header) => header,
+                None => break,
+            };
+            let mut consumed = 0;
+
+            match &id {
+                b"ds64" => {
+                    let mut ds64 = [0; 24];
+                    reader.read_exact(&mut ds64)?;
+                    data_size = Some(u64::from_le_bytes(ds64[8..16].try_into()?));
+                    consumed = ds64.len() as u64;
+                }
+                b"fmt " => {
+                    let mut fmt = vec![0; size as usize];
+                    reader.read_exact(&mut fmt)?;
+                    format = Some(Self::parse_fmt(&fmt)?);
+                    consumed = size;
+                }
+                b"data" => {
+                    if size == u32::MAX as u64 {
+                        size = data_size.unwrap_or(size);
+                    }
+                    data = Some((reader.stream_position()?, size));
+                    if format.is_some() {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+
+            skip_chunk(reader, size, consumed)?;
+        }
+
+        match (format, data) {
+            (Some(format), Some(data)) => Ok((format, data)),
+            (None, _) => bail!("missing fmt chunk"),
+            (_, None) => bail!("missing data chunk"),
+        }
+    }
+
+    fn parse_fmt(fmt: &[u8]) -> Result<Format> {
+        if fmt.len() < 16 {
+            bail!("truncated fmt chunk");
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+        let mut format = u16_at(0);
+        let channels = u16_at(2) as usize;
+        let sampling_rate = u32::from_le_bytes(fmt[4..8].try_into()?);
+        let block_align = u16_at(12) as usize;
+        let bits = u16_at(14) as usize;
+
+        let mut channel_mask = None;
+        if format == WAVE_FORMAT_EXTENSIBLE {
+            if fmt.len() < 40 {
+                bail!("truncated fmt chunk");
+            }
+            channel_mask = Some(u32::from_le_bytes(fmt[20..24].try_into()?)).filter(|m| *m != 0);
+            // The first two bytes of the sub-format GUID are the format tag.
+            format = u16_at(24);
+        }
+
+        if channels == 0 || block_align == 0 || !block_align.is_multiple_of(channels) {
+            bail!("invalid block alignment");
+        }
+
+        // Samples narrower than their container (e.g. 20 bits in 24) are
+        // left-aligned, so decoding by the container width is exact.
+        let width = block_align / channels;
+        if bits > width * 8 {
+            bail!("invalid bits per sample");
+        }
+
+        Ok(Format {
+            encoding: Encoding::wave(format, width * 8)?,
+            big_endian: false,
+            sampling_rate,
+            channels,
+            width,
+            channel_mask,
+        })
+    }
+
+    fn parse_aiff(reader: &mut (impl Read + Seek), aifc: bool) -> Result<(Format, (u64, u64))> {
+        let mut format = None;
+        let mut data = None;
+        loop {
+            let (id, size) = match read_chunk_header(reader, u32::from_be_bytes)? {
+                Some(header) => header,
+                None => break,
+            };
+            let mut consumed = 0;
+
+            match &id {
+                b"COMM" => {
+                    let mut comm = vec![0; size as usize];
+                    reader.read_exact(&mut comm)?;
+                    format = Some(Self::parse_comm(&comm, aifc)?);
+                    consumed = size;
+                }
+                b"SSND" => {
+                    let mut header = [0; 8];
+                    reader.read_exact(&mut header)?;
+                    let offset = u32::from_be_bytes(header[..4].try_into()?) as u64;
+                    let start = reader.stream_position()? + offset;
+                    data = Some((start, size.saturating_sub(8 + offset)));
+                    consumed = header.len() as u64;
+                }
+                _ => {}
+            }
+
+            skip_chunk(reader, size, consumed)?;
+        }
+
+        match (format, data) {
+            (Some((format, frames)), Some((start, size))) => {
+                let size = size.min(frames * (format.channels * format.width) as u64);
+                Ok((format, (start, size)))
+            }
+            (None, _) => bail!("missing COMM chunk"),
+            (_, None) => bail!("missing SSND chunk"),
+        }
+    }
+
+    fn parse_comm(comm: &[u8], aifc: bool) -> Result<(Format, u64)> {
+        if comm.len() < 18 || aifc && comm.len() < 22 {
+            bail!("truncated COMM chunk");
+        }
+
+        let channels = u16::from_be_bytes([comm[0], comm[1]]) as usize;
+        let frames = u32::from_be_bytes(comm[2..6].try_into()?) as u64;
+        let bits = u16::from_be_bytes([comm[6], comm[7]]) as usize;
+        let sampling_rate = from_extended(comm[8..18].try_into()?);
+        let compression = if aifc { &comm[18..22] } else { b"NONE" };
+
+        if channels == 0 || bits == 0 || !(1.0..=u32::MAX as f64).contains(&sampling_rate) {
+            bail!("invalid COMM chunk");
+        }
+
+        let width = bits.div_ceil(8);
+        Ok((
+            Format {
+                encoding: Encoding::aiff(compression, width * 8)?,
+                big_endian: compression != b"sowt",
+                sampling_rate: sampling_rate.round() as u32,
+                channels,
+                width,
+                channel_mask: None,
+            },
+            frames,
+        ))
+    }
+
+    /// Returns the format and the offset and size of the sample data.
+    fn parse(reader: &mut (impl Read + Seek)) -> Result<(Format, (u64, u64))> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        match (&header[..4], &header[8..]) {
+            (b"RIFF" | b"RF64" | b"BW64", b"WAVE") => Self::parse_wave(reader),
+            (b"FORM", b"AIFF" | b"AIFC") => Self::parse_aiff(reader, &header[8..] == b"AIFC"),
+            _ => bail!("unsupported container"),
+        }
+    }
+}
+
+/// Returns the range of the sample data of a WAVE or AIFF file.
+pub(super) fn audio_data(path: &Path) -> Result<Range<u64>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (_, (start, size)) = PcmReader::parse(&mut reader)?;
+    Ok(start..start + size)
+}
+
+impl Decoder for PcmReader {
+    fn from_reader(reader: Box<dyn Source>) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let (format, (start, size)) = Self::parse(&mut reader)?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        Ok(Self {
+            reader,
+            remaining: size,
+            buffer: Vec::new(),
+            format,
+        })
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        let format = &self.format;
+        let frame_size = (format.channels * format.width) as u64;
+        let frames = (buffer.len() / format.channels) as u64;
+        let len = (frames * frame_size).min(self.remaining - self.remaining % frame_size);
+
+        // Some writers leave the data size unset, so stop at the end of file.
+        self.buffer.clear();
+        (&mut self.reader).take(len).read_to_end(&mut self.buffer)?;
+        let len = self.buffer.len() - self.buffer.len() % frame_size as usize;
+        self.remaining -= len as u64;
+
+        for (sample, b) in buffer
+            .iter_mut()
+            .zip(self.buffer[..len].chunks_exact(format.width))
+        {
+            *sample = format.encoding.decode(b, format.big_endian);
+        }
+        Ok(len / frame_size as usize)
+    }
+
+    fn sampling_rate(&self) -> u32 {
+        self.format.sampling_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.format.channels
+    }
+
+    fn channel_layout(&self) -> Vec<Channel> {
+        match self.format.channel_mask {
+            Some(mask) => Channel::from_mask(mask, self.format.channels),
+            None => Channel::default_layout(self.format.channels),
+        }
+    }
+}
+
+fn read_chunk_header(
+    reader: &mut impl Read,
+    size: fn([u8; 4]) -> u32,
+) -> Result<Option<([u8; 4], u64)>> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => Ok(Some((
+            header[..4].try_into()?,
+            size(header[4..].try_into()?) as u64,
+        ))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn skip_chunk(reader: &mut impl Seek, size: u64, consumed: u64) -> Result<()> {
+    // Chunks are padded to an even size in both RIFF and IFF.
+    let skip = size.saturating_sub(consumed) + (size & 1);
+    reader.seek(SeekFrom::Current(skip as i64))?;
+    Ok(())
+}
+
+// Converts the 80-bit IEEE 754 extended precision value used by AIFF.
+fn from_extended(b: [u8; 10]) -> f64 {
+    let exponent = (u16::from_be_bytes([b[0], b[1]]) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes(b[2..].try_into().unwrap());
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if b[0] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::decoder::ReadFrame;
+    use std::fs;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_pcm() {
+        for path in ["test_data/sample.wav", "test_data/sample.aiff"] {
+            let mut r = PcmReader::open(path).unwrap();
+            assert_eq!(r.sampling_rate(), 48000, "{path}");
+            assert_eq!(r.channels(), 2, "{path}");
+
+            let mut frames = 0;
+            let mut peak = 0f64;
+            while let Some(sample) = r.read().unwrap() {
+                assert_eq!(sample[0], sample[1], "{path}");
+                peak = peak.max(sample[0].abs());
+                frames += 1;
+            }
+            assert_eq!(frames, 24576, "{path}");
+            assert!((peak - 10f64.powf(-18.0 / 20.0)).abs() < 1e-3, "{path}");
+        }
+    }
+
+    #[test]
+    fn rf64_extensible_float() {
+        let samples = [0.5f32, -0.25, 1.0, -1.0];
+        let data = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut ds64 = vec![0; 28];
+        ds64[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        fmt.extend_from_slice(&2u16.to_le_bytes());
+        fmt.extend_from_slice(&44100u32.to_le_bytes());
+        fmt.extend_from_slice(&(44100u32 * 8).to_le_bytes());
+        fmt.extend_from_slice(&8u16.to_le_bytes());
+        fmt.extend_from_slice(&32u16.to_le_bytes());
+        fmt.extend_from_slice(&22u16.to_le_bytes());
+        fmt.extend_from_slice(&32u16.to_le_bytes());
+        fmt.extend_from_slice(&3u32.to_le_bytes());
+        fmt.extend_from_slice(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        fmt.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+        ]);
+
+        let mut file = b"RF64\xFF\xFF\xFF\xFFWAVE".to_vec();
+        file.extend(chunk(b"ds64", &ds64));
+        file.extend(chunk(b"fmt ", &fmt));
+        file.extend(b"data\xFF\xFF\xFF\xFF");
+        file.extend(&data);
+
+        let path = std::env::temp_dir().join(format!("chksound-{}-rf64.wav", std::process::id()));
+        fs::write(&path, file).unwrap();
+
+        let mut r = PcmReader::open(&path).unwrap();
+        assert_eq!(r.sampling_rate(), 44100);
+        assert_eq!(r.channels(), 2);
+        assert_eq!(
+            r.channel_layout(),
+            [Channel::FrontLeft, Channel::FrontRight]
+        );
+        assert_eq!(r.read().unwrap(), Some(vec![0.5, -0.25]));
+        assert_eq!(r.read().unwrap(), Some(vec![1.0, -1.0]));
+        assert_eq!(r.read().unwrap(), None);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(Encoding::U8.decode(&[0], false), -1.0);
+        assert_eq!(Encoding::I8.decode(&[0xC0], true), -0.5);
+        assert_eq!(Encoding::I16.decode(&[0x00, 0x80], false), -1.0);
+        assert_eq!(Encoding::I16.decode(&[0x80, 0x00], true), -1.0);
+        assert_eq!(Encoding::I24.decode(&[0x00, 0x00, 0xC0], false), -0.5);
+        assert_eq!(Encoding::I32.decode(&[0x40, 0x00, 0x00, 0x00], true), 0.5);
+        assert_eq!(Encoding::F64.decode(&1.5f64.to_be_bytes(), true), 1.5);
+    }
+
+    #[test]
+    fn extended() {
+        let b = [0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0];
+        assert_eq!(from_extended(b), 48000.0);
+    }
+}
@@ -0,0 +1,554 @@
+use super::decoder::{Channel, Decoder, Source};
+use super::FrameLayout;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+pub struct SymphoniaReader {
+    path: Option<PathBuf>, // for messages, unless decoding from a stream.
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    sampling_rate: u32,
+    channels: usize,
+    channel_mask: u32,
+    // The decoded packet in planar layout and the range of frames not read yet.
+    buffer: Option<SampleBuffer<f64>>,
+    frames: usize,
+    position: usize,
+    end: usize,
+    // Frames still to drop and to return according to an MP4 edit list.
+    skip: usize,
+    left: Option<usize>,
+}
+
+impl SymphoniaReader {
+    fn new(mut source: Box<dyn MediaSource>, hint: &Hint, path: Option<&Path>) -> Result<Self> {
+        let edit = read_edit(&mut *source)?;
+        let source = MediaSourceStream::new(source, Default::default());
+        let probed = symphonia::default::get_probe().format(
+            hint,
+            source,
+            &FormatOptions {
+                enable_gapless: true,
+                ..Default::default()
+            },
+            &MetadataOptions::default(),
+        )?;
+        let format = probed.format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow!("no audio track"))?;
+        let params = &track.codec_params;
+        let sampling_rate = params
+            .sample_rate
+            .ok_or_else(|| anyhow!("unknown sampling rate"))?;
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+        let track_id = track.id;
+        let (skip, left) = match edit {
+            Some(edit) => (edit.skip(sampling_rate), Some(edit.len(sampling_rate))),
+            None => (0, None),
+        };
+
+        // Containers such as MP4 may leave the channel layout to the codec,
+        // in which case it is only known after decoding the first packet.
+        let layout = decoder.codec_params().channels.or(params.channels);
+
+        let mut reader = Self {
+            path: path.map(Path::to_path_buf),
+            format,
+            decoder,
+            track_id,
+            sampling_rate,
+            channels: layout.map_or(0, |c| c.count()),
+            channel_mask: layout.map_or(0, |c| c.bits()),
+            buffer: None,
+            frames: 0,
+            position: 0,
+            end: 0,
+            skip,
+            left,
+        };
+        if layout.is_none() && !reader.decode()? {
+            anyhow::bail!("unknown channel layout");
+        }
+
+        Ok(reader)
+    }
+
+    fn remaining(&self) -> usize {
+        self.end - self.position
+    }
+
+    fn decode(&mut self) -> Result<bool> {
+        loop {
+            if self.left == Some(0) {
+                return Ok(false);
+            }
+
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(e)) => {
+                    // A corrupt packet is skipped rather than aborting the analysis.
+                    match self.path {
+                        Some(ref path) => log::warn!("{}: {e}", path.display()),
+                        None => log::warn!("{e}"),
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let layout = decoded.spec().channels;
+            let channels = layout.count();
+            if self.channels == 0 {
+                self.channels = channels;
+                self.channel_mask = layout.bits();
+            } else if channels != self.channels {
+                anyhow::bail!("channel layout changed");
+            }
+
+            let buffer = match &mut self.buffer {
+                Some(buffer) if buffer.capacity() >= decoded.capacity() * self.channels => buffer,
+                buffer => buffer.insert(SampleBuffer::new(
+                    decoded.capacity() as u64,
+                    *decoded.spec(),
+                )),
+            };
+            buffer.copy_planar_ref(decoded);
+            self.frames = buffer.len() / self.channels;
+
+            // With gapless playback enabled, the packet tells how many
+            // frames of encoder delay and padding to drop.
+            self.end = self.frames.saturating_sub(packet.trim_end() as usize);
+            self.position = (packet.trim_start() as usize).min(self.end);
+
+            let skip = self.skip.min(self.end - self.position);
+            self.position += skip;
+            self.skip -= skip;
+            if let Some(ref mut left) = self.left {
+                self.end = self.end.min(self.position + *left);
+                *left -= self.end - self.position;
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl Decoder for SymphoniaReader {
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        Self::new(Box::new(File::open(path)?), &hint, Some(path))
+    }
+
+    fn from_reader(reader: Box<dyn Source>) -> Result<Self> {
+        Self::new(Box::new(SourceAdapter::new(reader)?), &Hint::new(), None)
+    }
+
+    fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn channel_layout(&self) -> Vec<Channel> {
+        // Symphonia numbers the channels like WAVE channel masks.
+        Channel::from_mask(self.channel_mask, self.channels)
+    }
+
+    /// Returns the frames of one packet at most, as planar blocks can not be
+    /// extended once written.
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        while self.remaining() == 0 {
+            if !self.decode()? {
+                return Ok(0);
+            }
+        }
+
+        let samples = self.buffer.as_ref().unwrap().samples();
+        let n = self.remaining().min(buffer.len() / self.channels);
+        for (ch, channel) in buffer.chunks_exact_mut(n).take(self.channels).enumerate() {
+            let start = ch * self.frames + self.position;
+            channel.copy_from_slice(&samples[start..start + n]);
+        }
+        self.position += n;
+        Ok(n)
+    }
+
+    fn frame_layout(&self) -> FrameLayout {
+        FrameLayout::Planar
+    }
+}
+
+/// Lets symphonia read from any seekable stream.
+struct SourceAdapter {
+    reader: Box<dyn Source>,
+    len: u64,
+}
+
+impl SourceAdapter {
+    fn new(mut reader: Box<dyn Source>) -> io::Result<Self> {
+        let position = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(Self { reader, len })
+    }
+}
+
+impl Read for SourceAdapter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Seek for SourceAdapter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl MediaSource for SourceAdapter {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+/// The segment of an MP4 sound track to play. Symphonia parses edit lists
+/// but ignores them, while AAC encoders use them to mark the priming and
+/// padding frames.
+struct Edit {
+    media_time: u64,
+    media_timescale: u32,
+    duration: u64,
+    movie_timescale: u32,
+}
+
+impl Edit {
+    /// Returns the number of frames to drop at the start.
+    fn skip(&self, sampling_rate: u32) -> usize {
+        (self.media_time * sampling_rate as u64 / self.media_timescale as u64) as usize
+    }
+
+    /// Returns the number of frames to play.
+    fn len(&self, sampling_rate: u32) -> usize {
+        (self.duration * sampling_rate as u64 / self.movie_timescale as u64) as usize
+    }
+}
+
+/// Reads the edit list of the first sound track if the stream is an MP4
+/// file, leaving the stream position unchanged.
+fn read_edit<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<Option<Edit>> {
+    let start = reader.stream_position()?;
+    let edit = find_edit(reader, start).unwrap_or_else(|e| {
+        log::debug!("ignoring edit list: {e}");
+        None
+    });
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(edit)
+}
+
+fn find_edit<R: Read + Seek + ?Sized>(reader: &mut R, start: u64) -> Result<Option<Edit>> {
+    if &read_at(reader, start + 4, 4)?[..] != b"ftyp" {
+        return Ok(None);
+    }
+
+    let len = reader.seek(SeekFrom::End(0))?;
+    let Some(moov) = find(&atoms(reader, start, len)?, b"moov") else {
+        return Ok(None);
+    };
+    let moov = atoms(reader, moov.0, moov.1)?;
+    let Some(mvhd) = find(&moov, b"mvhd") else {
+        return Ok(None);
+    };
+    let movie_timescale = timescale(reader, mvhd)?;
+
+    for trak in moov.iter().filter(|a| &a.0 == b"trak") {
+        let trak = atoms(reader, trak.1, trak.2)?;
+        let Some(mdia) = find(&trak, b"mdia") else {
+            continue;
+        };
+        let mdia = atoms(reader, mdia.0, mdia.1)?;
+        let (Some(hdlr), Some(mdhd)) = (find(&mdia, b"hdlr"), find(&mdia, b"mdhd")) else {
+            continue;
+        };
+        if &read_at(reader, hdlr.0 + 8, 4)?[..] != b"soun" {
+            continue;
+        }
+
+        let media_timescale = timescale(reader, mdhd)?;
+        let Some(edts) = find(&trak, b"edts") else {
+            return Ok(None);
+        };
+        let Some(elst) = find(&atoms(reader, edts.0, edts.1)?, b"elst") else {
+            return Ok(None);
+        };
+        let elst = read_at(reader, elst.0, elst.1 - elst.0)?;
+        if movie_timescale == 0 || media_timescale == 0 {
+            anyhow::bail!("invalid timescale");
+        }
+        return Ok(segment(&elst).map(|(media_time, duration)| Edit {
+            media_time,
+            media_timescale,
+            duration,
+            movie_timescale,
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Returns the body ranges of the mdat atoms of an MP4 file, which hold the
+/// encoded audio.
+pub(super) fn mdat_ranges(path: &Path) -> Result<Vec<Range<u64>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(atoms(&mut file, 0, len)?
+        .into_iter()
+        .filter(|a| &a.0 == b"mdat")
+        .map(|(_, start, end)| start..end)
+        .collect())
+}
+
+/// Returns the type and the body range of each atom between `start` and `end`.
+fn atoms<R: Read + Seek + ?Sized>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+) -> Result<Vec<([u8; 4], u64, u64)>> {
+    let mut atoms = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let header = read_at(reader, pos, 8)?;
+        let (body, size) = match u32::from_be_bytes(header[..4].try_into()?) {
+            0 => (pos + 8, end - pos),
+            1 => (
+                pos + 16,
+                u64::from_be_bytes(read_at(reader, pos + 8, 8)?[..].try_into()?),
+            ),
+            size => (pos + 8, size as u64),
+        };
+        if size < body - pos || end - pos < size {
+            anyhow::bail!("invalid atom size");
+        }
+        atoms.push((header[4..].try_into()?, body, pos + size));
+        pos += size;
+    }
+    Ok(atoms)
+}
+
+fn find(atoms: &[([u8; 4], u64, u64)], kind: &[u8; 4]) -> Option<(u64, u64)> {
+    atoms
+        .iter()
+        .find(|a| &a.0 == kind)
+        .map(|&(_, start, end)| (start, end))
+}
+
+fn read_at<R: Read + Seek + ?Sized>(reader: &mut R, pos: u64, len: u64) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut data = vec![0; usize::try_from(len)?];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Reads the timescale of an mvhd or mdhd atom.
+fn timescale<R: Read + Seek + ?Sized>(reader: &mut R, atom: (u64, u64)) -> Result<u32> {
+    let offset = match read_at(reader, atom.0, 1)?[0] {
+        1 => 20,
+        _ => 12,
+    };
+    Ok(u32::from_be_bytes(
+        read_at(reader, atom.0 + offset, 4)?[..].try_into()?,
+    ))
+}
+
+/// Returns the media time and duration of the only segment of an elst atom.
+/// Empty edits, which delay the track, are skipped.
+fn segment(elst: &[u8]) -> Option<(u64, u64)> {
+    let version = *elst.first()?;
+    let count = u32::from_be_bytes(elst.get(4..8)?.try_into().ok()?) as usize;
+    let size = if version == 1 { 20 } else { 12 };
+
+    let mut segment = None;
+    for i in 0..count {
+        let entry = elst.get(8 + i * size..8 + (i + 1) * size)?;
+        let (duration, media_time) = if version == 1 {
+            (
+                u64::from_be_bytes(entry[..8].try_into().ok()?),
+                i64::from_be_bytes(entry[8..16].try_into().ok()?),
+            )
+        } else {
+            (
+                u32::from_be_bytes(entry[..4].try_into().ok()?) as u64,
+                i32::from_be_bytes(entry[4..8].try_into().ok()?) as i64,
+            )
+        };
+        if media_time < 0 {
+            continue;
+        }
+        if segment.is_some() {
+            return None;
+        }
+        segment = Some((media_time as u64, duration));
+    }
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::decoder::ReadFrame;
+
+    #[test]
+    fn test_flac() {
+        let mut r = SymphoniaReader::open("test_data/sample.flac").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut frames = 0;
+        let mut peak = 0f64;
+        while let Some(sample) = r.read().unwrap() {
+            assert_eq!(sample.len(), 2);
+            peak = sample.iter().fold(peak, |p, s| p.max(s.abs()));
+            frames += 1;
+        }
+        assert_eq!(frames, 4096 * 12);
+        assert!((peak - 10f64.powf(-18.0 / 20.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_ogg() {
+        use crate::audio::{Analyzer, PeakMode};
+
+        let mut r = SymphoniaReader::open("test_data/sample.ogg").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut analyzer = Analyzer::new(r.sampling_rate(), &r.channel_layout(), PeakMode::Sample);
+        let mut buffer = vec![0.0; 4096 * 2];
+        let mut frames = 0;
+        loop {
+            let n = r.read_frames(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            analyzer.add_frames(&buffer[..n * 2], r.frame_layout());
+            frames += n;
+        }
+        assert_eq!(frames, 48000);
+
+        // A 1 kHz sine at -20 dBFS in both channels, which measures -20 LUFS.
+        let analysis = analyzer.flush();
+        let loudness = f64::from(analysis.stats.get_mean(-10.0));
+        assert!((loudness + 20.0).abs() < 0.1, "{loudness}");
+        assert!((analysis.peak - 0.1).abs() < 0.005, "{}", analysis.peak);
+    }
+
+    #[test]
+    fn test_m4a() {
+        use crate::audio::{Analyzer, PeakMode};
+
+        let mut r = SymphoniaReader::open("test_data/sample.m4a").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut analyzer = Analyzer::new(r.sampling_rate(), &r.channel_layout(), PeakMode::Sample);
+        let mut buffer = vec![0.0; 4096 * 2];
+        let mut frames = 0;
+        loop {
+            let n = r.read_frames(&mut buffer).unwrap();
+            if n == 0 {
+                break;
+            }
+            analyzer.add_frames(&buffer[..n * 2], r.frame_layout());
+            frames += n;
+        }
+        // The edit list drops the 1024 priming frames and the padding of the
+        // last packet, leaving the 19 s the movie plays.
+        assert_eq!(frames, 19 * 48000);
+
+        // A 1 kHz sine at -20 dBFS in both channels, which measures -20 LUFS.
+        let loudness = f64::from(analyzer.flush().stats.get_mean(-10.0));
+        assert!((loudness + 20.0).abs() < 0.1, "{loudness}");
+    }
+
+    #[test]
+    fn edit_list() {
+        let elst = |entries: &[(u32, i32)]| {
+            let mut elst = vec![0, 0, 0, 0];
+            elst.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+            for (duration, media_time) in entries {
+                elst.extend_from_slice(&duration.to_be_bytes());
+                elst.extend_from_slice(&media_time.to_be_bytes());
+                elst.extend_from_slice(&[0, 1, 0, 0]);
+            }
+            elst
+        };
+        assert_eq!(segment(&elst(&[(19000, 1024)])), Some((1024, 19000)));
+        assert_eq!(
+            segment(&elst(&[(500, -1), (19000, 2112)])),
+            Some((2112, 19000))
+        );
+        assert_eq!(segment(&elst(&[(1000, 0), (1000, 48000)])), None);
+        assert_eq!(segment(&elst(&[(19000, 1024)])[..16]), None);
+    }
+
+    #[cfg(feature = "symphonia-mp3")]
+    #[test]
+    fn test_mp3() {
+        let mut r = SymphoniaReader::open("test_data/sample.mp3").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut frames = 0;
+        while r.read().unwrap().is_some() {
+            frames += 1;
+        }
+        // The encoder delay and padding from the LAME header are trimmed.
+        assert_eq!(frames, 911522);
+    }
+
+    #[test]
+    fn test_caf() {
+        let mut r = SymphoniaReader::open("test_data/sample.caf").unwrap();
+        assert_eq!(r.sampling_rate(), 48000);
+        assert_eq!(r.channels(), 2);
+
+        let mut frames = 0;
+        while let Some(sample) = r.read().unwrap() {
+            assert_eq!(sample[0], sample[1]);
+            frames += 1;
+        }
+        assert_eq!(frames, 24576);
+    }
+}
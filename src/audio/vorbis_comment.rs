@@ -0,0 +1,176 @@
+use super::ReplayGain;
+use anyhow::{bail, Result};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VorbisComment {
+    vendor: String,
+    /// Comments as stored, `KEY=value`. Malformed ones without `=` are kept
+    /// and written back unchanged.
+    comments: Vec<String>,
+}
+
+impl VorbisComment {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+
+        let vendor = reader.read_string()?;
+        let count = reader.read_u32()?;
+        let mut comments = Vec::new();
+        for _ in 0..count {
+            let comment = reader.read_string()?;
+            if !comment.contains('=') {
+                log::warn!("keeping malformed comment: {comment}");
+            }
+            comments.push(comment);
+        }
+
+        Ok(Self { vendor, comments })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_string(&mut data, &self.vendor);
+        data.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            write_string(&mut data, comment);
+        }
+        data
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .filter_map(|c| c.split_once('='))
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        self.remove(key);
+        self.comments.push(format!("{key}={}", value.into()));
+    }
+
+    /// Removes all comments named `key` and returns whether there were any.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.comments.len();
+        self.comments
+            .retain(|c| !matches!(c.split_once('='), Some((k, _)) if k.eq_ignore_ascii_case(key)));
+        self.comments.len() != len
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.get("ARTIST")
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.get("ALBUM")
+    }
+
+    pub fn album_artist(&self) -> Option<&str> {
+        self.get("ALBUMARTIST").or_else(|| self.get("ALBUM ARTIST"))
+    }
+
+    pub fn musicbrainz_album_id(&self) -> Option<&str> {
+        self.get("MUSICBRAINZ_ALBUMID")
+    }
+
+    pub fn disc(&self) -> Option<u32> {
+        self.get("DISCNUMBER")
+            .and_then(|s| s.split('/').next())
+            .and_then(|s| s.trim().parse().ok())
+    }
+
+    pub fn compilation(&self) -> bool {
+        matches!(self.get("COMPILATION"), Some(s) if s.trim() != "0")
+    }
+
+    pub fn set_replaygain(&mut self, val: &ReplayGain) {
+        for (name, value) in val.tags() {
+            self.set(name, value);
+        }
+    }
+
+    pub fn remove_replaygain(&mut self) -> bool {
+        let mut removed = false;
+        for name in ReplayGain::NAMES {
+            removed |= self.remove(name);
+        }
+        removed
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn read_u32(&mut self) -> Result<u32> {
+        if self.0.len() < 4 {
+            bail!("truncated comment header");
+        }
+
+        let (bytes, rest) = self.0.split_at(4);
+        self.0 = rest;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_u32()? as usize;
+        if self.0.len() < len {
+            bail!("truncated comment header");
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+fn write_string(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut comment = VorbisComment::default();
+        comment.set("ARTIST", "Artist");
+        comment.set("album", "Album");
+        comment.set("Artist", "Other");
+
+        let parsed = VorbisComment::parse(&comment.to_bytes()).unwrap();
+        assert_eq!(parsed, comment);
+        assert_eq!(parsed.get("artist"), Some("Other"));
+        assert_eq!(parsed.get("ALBUM"), Some("Album"));
+    }
+
+    #[test]
+    fn truncated() {
+        let comment = VorbisComment {
+            vendor: "vendor".to_string(),
+            comments: vec!["ARTIST=Artist".to_string()],
+        };
+        let data = comment.to_bytes();
+        assert!(VorbisComment::parse(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let mut comment = VorbisComment {
+            vendor: "vendor".to_string(),
+            comments: vec!["ARTIST".to_string(), "ALBUM=Album".to_string()],
+        };
+        let data = comment.to_bytes();
+        let mut parsed = VorbisComment::parse(&data).unwrap();
+        assert_eq!(parsed, comment);
+        assert_eq!(parsed.get("ARTIST"), None);
+        assert_eq!(parsed.to_bytes(), data);
+
+        assert!(!parsed.remove("ARTIST"));
+        parsed.set("ALBUM", "Other");
+        comment.comments[1] = "ALBUM=Other".to_string();
+        assert_eq!(parsed, comment);
+    }
+}
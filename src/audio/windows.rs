@@ -1,3 +1,4 @@
+use super::decoder::{is_mp4, is_mpeg_audio, is_wave, Backend, Decoder};
 use anyhow::Result;
 use once_cell::sync::OnceCell as SyncOnceCell;
 use std::path::Path;
@@ -10,7 +11,16 @@ const MF_VERSION: u32 = MF_SDK_VERSION << 16 | MF_API_VERSION;
 
 static MF: SyncOnceCell<()> = SyncOnceCell::new();
 
-pub struct AudioReader {
+/// Returns Media Foundation, which is preferred for the formats it decodes.
+pub fn backends() -> Vec<Backend> {
+    vec![Backend::new::<MediaFoundationReader>(
+        "mediafoundation",
+        &["m4a", "mp3", "wav", "wave"],
+        |h| is_mp4(h) || is_mpeg_audio(h) || is_wave(h),
+    )]
+}
+
+pub struct MediaFoundationReader {
     reader: IMFSourceReader,
     sampling_rate: u32,
     channels: usize,
@@ -18,8 +28,46 @@ pub struct AudioReader {
     position: usize,
 }
 
-impl AudioReader {
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+impl MediaFoundationReader {
+    /// Reads the next sample, returning `false` at the end of the stream.
+    fn decode(&mut self) -> Result<bool> {
+        let mut flags = 0;
+        let mut sample = None;
+        unsafe {
+            self.reader.ReadSample(
+                MF_SOURCE_READER_FIRST_AUDIO_STREAM.0 as _,
+                0,
+                None,
+                Some(&mut flags),
+                None,
+                Some(&mut sample),
+            )?
+        };
+        if flags as i32 & MF_SOURCE_READERF_ENDOFSTREAM.0 == MF_SOURCE_READERF_ENDOFSTREAM.0 {
+            return Ok(false);
+        }
+        if flags != 0 {
+            anyhow::bail!("{flags}");
+        }
+
+        let buffer = unsafe { sample.unwrap().ConvertToContiguousBuffer()? };
+        let mut pointer: *mut u8 = null_mut();
+        let mut length = 0;
+        unsafe { buffer.Lock(&mut pointer, None, Some(&mut length))? };
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(unsafe {
+            from_raw_parts(pointer as *const f32, (length / 4) as usize)
+        });
+        self.position = 0;
+
+        unsafe { buffer.Unlock()? };
+        Ok(true)
+    }
+}
+
+impl Decoder for MediaFoundationReader {
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
         unsafe {
             MF.get_or_try_init(|| MFStartup(MF_VERSION, MFSTARTUP_LITE))?;
 
@@ -58,56 +106,30 @@ impl AudioReader {
         }
     }
 
-    pub fn read(&mut self) -> Result<Option<Vec<f64>>> {
-        if self.position == self.buffer.len() {
-            let mut flags = 0;
-            let mut sample = None;
-            unsafe {
-                self.reader.ReadSample(
-                    MF_SOURCE_READER_FIRST_AUDIO_STREAM.0 as _,
-                    0,
-                    None,
-                    Some(&mut flags),
-                    None,
-                    Some(&mut sample),
-                )?
-            };
-            if flags as i32 & MF_SOURCE_READERF_ENDOFSTREAM.0 == MF_SOURCE_READERF_ENDOFSTREAM.0 {
-                return Ok(None);
-            }
-            if flags != 0 {
-                anyhow::bail!("{flags}");
+    fn read_frames(&mut self, buffer: &mut [f64]) -> Result<usize> {
+        let len = buffer.len() - buffer.len() % self.channels;
+        let mut filled = 0;
+        while filled < len {
+            if self.position == self.buffer.len() && !self.decode()? {
+                break;
             }
 
-            let buffer = unsafe { sample.unwrap().ConvertToContiguousBuffer()? };
-            let mut pointer: *mut u8 = null_mut();
-            let mut length = 0;
-            unsafe { buffer.Lock(&mut pointer, None, Some(&mut length))? };
-
-            self.buffer =
-                unsafe { from_raw_parts(pointer as *const f32, (length / 4) as usize).to_vec() };
-            self.position = 0;
-
-            unsafe { buffer.Unlock()? };
-        }
-
-        if self.position < self.buffer.len() {
-            let sample = self.buffer[self.position..self.position + self.channels]
-                .iter()
-                .map(|s| *s as f64)
-                .collect();
-            self.position += self.channels;
-            Ok(Some(sample))
-        } else {
-            Ok(None)
+            let n = (self.buffer.len() - self.position).min(len - filled);
+            let samples = &self.buffer[self.position..self.position + n];
+            for (out, sample) in buffer[filled..filled + n].iter_mut().zip(samples) {
+                *out = *sample as f64;
+            }
+            self.position += n;
+            filled += n;
         }
+        Ok(filled / self.channels)
     }
 
-    pub fn sampling_rate(&self) -> u32 {
+    fn sampling_rate(&self) -> u32 {
         self.sampling_rate
     }
 
-    pub fn channels(&self) -> usize {
+    fn channels(&self) -> usize {
         self.channels
     }
 }
@@ -118,14 +140,14 @@ mod tests {
 
     #[test]
     fn test_mp3() {
-        let r = AudioReader::open("test_data/sample.mp3").unwrap();
+        let r = MediaFoundationReader::open("test_data/sample.mp3").unwrap();
         assert_eq!(r.sampling_rate(), 48000);
         assert_eq!(r.channels(), 2);
     }
 
     #[test]
     fn test_m4a() {
-        let r = AudioReader::open("test_data/sample.m4a").unwrap();
+        let r = MediaFoundationReader::open("test_data/sample.m4a").unwrap();
         assert_eq!(r.sampling_rate(), 48000);
         assert_eq!(r.channels(), 2);
     }
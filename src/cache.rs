@@ -0,0 +1,347 @@
+use crate::audio::{self, Analysis, PeakMode};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    hash: Option<u64>,
+    analysis: Analysis,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: Vec<Entry>,
+}
+
+/// Persistent store of analysis results keyed by path, and size and modification time or a hash
+/// of the audio data.
+pub struct Cache {
+    path: PathBuf,
+    hash: bool,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Cache {
+    const VERSION: u32 = 3;
+
+    /// Opens the cache stored in `path`. A missing or unreadable file results in an empty cache.
+    /// If `hash` is set, entries are validated against a hash of the audio data instead of the
+    /// size and modification time, so they survive retagging.
+    pub fn open(path: impl AsRef<Path>, hash: bool) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let entries = match File::open(&path) {
+            Ok(file) => match serde_json::from_reader::<_, CacheFile>(BufReader::new(file)) {
+                Ok(file) if file.version == Self::VERSION => file.entries,
+                Ok(file) => {
+                    log::warn!(
+                        "{}: ignoring cache version {}",
+                        path.display(),
+                        file.version
+                    );
+                    Vec::new()
+                }
+                Err(e) => {
+                    log::warn!("{}: ignoring unreadable cache: {e}", path.display());
+                    Vec::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            hash,
+            entries: entries.into_iter().map(|e| (e.path.clone(), e)).collect(),
+        })
+    }
+
+    /// Returns the cached analysis of the file if it has not changed since it was stored and
+    /// it contains the peak measurement requested by `mode`.
+    pub fn get(&self, path: &Path, mode: PeakMode) -> Option<Analysis> {
+        let path = fs::canonicalize(path).ok()?;
+        let entry = self.entries.get(&path)?;
+
+        if self.hash {
+            if entry.hash != Some(hash_audio(&path).ok()?) {
+                return None;
+            }
+        } else {
+            let metadata = fs::metadata(&path).ok()?;
+            if entry.size != metadata.len() || entry.modified != metadata.modified().ok()? {
+                return None;
+            }
+        }
+
+        if mode == PeakMode::True && entry.analysis.true_peak.is_none() {
+            return None;
+        }
+
+        Some(entry.analysis.clone())
+    }
+
+    /// Stores the analysis of the file in its current state.
+    pub fn insert(&mut self, path: &Path, analysis: Analysis) -> Result<()> {
+        let path = fs::canonicalize(path)?;
+        if path.to_str().is_none() {
+            // Non UTF-8 paths can not be serialized.
+            return Ok(());
+        }
+
+        let metadata = fs::metadata(&path)?;
+        let hash = if self.hash {
+            Some(hash_audio(&path)?)
+        } else {
+            None
+        };
+
+        let entry = Entry {
+            path: path.clone(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            hash,
+            analysis,
+        };
+        self.entries.insert(path, entry);
+
+        Ok(())
+    }
+
+    pub fn save(self) -> Result<()> {
+        let mut entries = self.entries.into_values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let file = CacheFile {
+            version: Self::VERSION,
+            entries,
+        };
+
+        // Only a completely written file may replace the previous cache.
+        audio::replace_file(&self.path, |writer| {
+            serde_json::to_writer(writer, &file)?;
+            Ok(())
+        })
+    }
+}
+
+// 64-bit FNV-1a, which is stable across Rust releases unlike DefaultHasher.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Hashes the audio data of the file, leaving out the tags.
+fn hash_audio(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = Fnv1a::default();
+    let mut buf = vec![0; 64 * 1024];
+    for range in audio::audio_data(path)? {
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut reader = (&mut file).take(range.end - range.start);
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                n => hasher.write(&buf[..n]),
+            }
+        }
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{
+        Analyzer, AudioFile, Channel, FlacFile, FrameLayout, Id3File, M4aFile, OggFile, OpusFile,
+        ReplayGain,
+    };
+
+    #[test]
+    fn fnv1a() {
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.finish(), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn unreadable() {
+        let path = std::env::temp_dir().join(format!("chksound-{}-cache.json", std::process::id()));
+        fs::write(&path, b"{\"version\": 2, \"entr").unwrap();
+
+        let cache = Cache::open(&path, false).unwrap();
+        assert!(cache.entries.is_empty());
+
+        // Saving replaces the damaged file.
+        cache.save().unwrap();
+        let file: CacheFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file.version, Cache::VERSION);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalidate() {
+        let dir = std::env::temp_dir().join(format!("chksound-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("track.mp3");
+        fs::write(&path, b"foo").unwrap();
+
+        let mut cache = Cache::open(dir.join("cache.json"), true).unwrap();
+        let analysis = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample).flush();
+        cache.insert(&path, analysis).unwrap();
+        assert!(cache.get(&path, PeakMode::Sample).is_some());
+        assert!(cache.get(&path, PeakMode::True).is_none());
+
+        cache.save().unwrap();
+        let cache = Cache::open(dir.join("cache.json"), true).unwrap();
+        assert!(cache.get(&path, PeakMode::Sample).is_some());
+
+        fs::write(&path, b"bar").unwrap();
+        assert!(cache.get(&path, PeakMode::Sample).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn audio_hash_ignores_tags() {
+        let dir = std::env::temp_dir().join(format!("chksound-cache-{}-tags", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let val = ReplayGain {
+            track_gain: -6.5,
+            track_peak: 0.5,
+            album_gain: -6.5,
+            album_peak: 0.5,
+            reference: -18.0,
+        };
+
+        for name in ["flac", "m4a", "mp3", "ogg", "opus", "wav"] {
+            let path = dir.join(format!("sample.{name}"));
+            fs::copy(format!("test_data/sample.{name}"), &path).unwrap();
+            let hash = hash_audio(&path).unwrap();
+            let size = fs::metadata(&path).unwrap().len();
+
+            let mut file: Box<dyn AudioFile> = match name {
+                "flac" => Box::new(FlacFile::open(&path).unwrap()),
+                "m4a" => Box::new(M4aFile::open(&path).unwrap()),
+                "ogg" => Box::new(OggFile::open(&path).unwrap()),
+                "opus" => Box::new(OpusFile::open(&path).unwrap()),
+                _ => Box::new(Id3File::open(&path).unwrap()),
+            };
+            file.set_normalization(&" 00000001".repeat(1000));
+            file.set_replaygain(&val);
+            file.save().unwrap();
+
+            assert_ne!(fs::metadata(&path).unwrap().len(), size, "{name}");
+            assert_eq!(hash_audio(&path).unwrap(), hash, "{name}");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retagged() {
+        let dir = std::env::temp_dir().join(format!("chksound-cache-{}-retag", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sample.flac");
+        fs::copy("test_data/sample.flac", &path).unwrap();
+
+        let analysis = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample).flush();
+        let mut by_hash = Cache::open(dir.join("hash.json"), true).unwrap();
+        by_hash.insert(&path, analysis.clone()).unwrap();
+        let mut by_size = Cache::open(dir.join("size.json"), false).unwrap();
+        by_size.insert(&path, analysis).unwrap();
+
+        let mut file = FlacFile::open(&path).unwrap();
+        file.set_normalization(&" 00000001".repeat(1000));
+        file.save().unwrap();
+        assert!(by_hash.get(&path, PeakMode::Sample).is_some());
+        assert!(by_size.get(&path, PeakMode::Sample).is_none());
+
+        // Changing a byte of the last audio frame invalidates the entry.
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 3;
+        data[last] ^= 0x01;
+        fs::write(&path, data).unwrap();
+        assert!(by_hash.get(&path, PeakMode::Sample).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn temporary_file() {
+        let dir = std::env::temp_dir().join(format!("chksound-cache-{}-temp", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cache.tmp"), b"unrelated").unwrap();
+        let path = dir.join("track.mp3");
+        fs::write(&path, b"foo").unwrap();
+        let analysis = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::Sample).flush();
+
+        // Neither a sibling named like a temporary file nor a cache named so is clobbered.
+        for name in ["cache.json", "cache.tmp"] {
+            let mut cache = Cache::open(dir.join(name), false).unwrap();
+            cache.insert(&path, analysis.clone()).unwrap();
+            cache.save().unwrap();
+            let cache = Cache::open(dir.join(name), false).unwrap();
+            assert!(cache.get(&path, PeakMode::Sample).is_some(), "{name}");
+            assert_eq!(
+                fs::read(dir.join("cache.tmp")).unwrap() == b"unrelated",
+                name == "cache.json"
+            );
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut analyzer = Analyzer::new(48000, &Channel::default_layout(2), PeakMode::True);
+        for i in 0..48000 * 5 {
+            let x = 0.25 * (i as f64 * 0.1).sin() * (i as f64 * 1e-4).sin();
+            analyzer.add_frames(&[x, -x], FrameLayout::Interleaved);
+        }
+        let analysis = analyzer.flush();
+
+        let json = serde_json::to_string(&analysis).unwrap();
+        let restored: Analysis = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+        assert_eq!(
+            f64::from(restored.stats.get_mean(-10.0)).to_bits(),
+            f64::from(analysis.stats.get_mean(-10.0)).to_bits()
+        );
+        assert_eq!(
+            f64::from(restored.short_term.get_loudness_range()).to_bits(),
+            f64::from(analysis.short_term.get_loudness_range()).to_bits()
+        );
+        assert_eq!(restored.true_peak, analysis.true_peak);
+        assert_eq!(restored.stats, analysis.stats);
+        assert_eq!(restored.short_term, analysis.short_term);
+    }
+}
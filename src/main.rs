@@ -1,12 +1,20 @@
 mod audio;
+mod cache;
+mod report;
 
-use audio::{bs1770::Stats, Aggregator, Analyzer, AudioFile, AudioReader, M4aFile, Mp3File};
-use clap::Parser;
+use audio::{
+    bs1770, Aggregator, Analysis, Analyzer, AudioFile, FlacFile, Format, ITunNorm, Id3File,
+    M4aFile, OggFile, OpusFile, PeakMode, Registry, ReplayGain,
+};
+use cache::Cache;
+use clap::{Parser, ValueEnum};
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
-use std::collections::HashMap;
+use report::{Record, Report};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -16,56 +24,276 @@ type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
 struct Args {
     /// Files or directories to analyze.
     paths: Vec<PathBuf>,
+
+    /// Analyze files and show the values that would be written without saving them.
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// Compare the stored iTunNORM values with a fresh analysis without saving them. Exits with
+    /// a non-zero status if any file deviates or can not be analyzed.
+    #[arg(long, conflicts_with = "dry_run")]
+    verify: bool,
+
+    /// Remove iTunNORM and ReplayGain tags without analyzing the files.
+    #[arg(long, conflicts_with_all = ["dry_run", "verify"])]
+    strip: bool,
+
+    /// Maximum deviation of gain and peak in dB accepted by --verify.
+    #[arg(long, value_name = "DB", default_value_t = 0.5, requires = "verify")]
+    tolerance: f64,
+
+    /// Loudness standard that defines the reference level and the relative gate.
+    #[arg(long, value_enum, default_value_t = Preset::ReplayGain)]
+    preset: Preset,
+
+    /// Reference loudness in LUFS. Overrides the preset.
+    #[arg(long, value_name = "LUFS", allow_negative_numbers = true)]
+    target: Option<f64>,
+
+    /// Relative gate in LU. Overrides the preset.
+    #[arg(long, value_name = "LU", allow_negative_numbers = true)]
+    gate: Option<f64>,
+
+    /// How tracks are grouped into albums for album gain and peak.
+    #[arg(long, value_enum, default_value_t = Grouping::Artist)]
+    group_by: Grouping,
+
+    /// Decoder to use for all files instead of choosing one by content and extension.
+    #[arg(long, value_name = "NAME")]
+    decoder: Option<String>,
+
+    /// Peak measurement used for the values written to tags.
+    #[arg(long, value_enum, default_value_t = PeakMode::Sample)]
+    peak: PeakMode,
+
+    /// Also write ReplayGain 2.0 tags.
+    #[arg(short, long)]
+    replaygain: bool,
+
+    /// Store the album gain in the output gain of the OpusHead header and write the R128 tags
+    /// relative to it. The original output gain is kept in a CHKSOUND_ORIGINAL_OUTPUT_GAIN
+    /// comment, which --strip restores.
+    #[arg(long)]
+    opus_output_gain: bool,
+
+    /// Reuse analysis results of unchanged files stored in the cache file.
+    #[arg(long, value_name = "PATH")]
+    cache: Option<PathBuf>,
+
+    /// Validate cached entries by a hash of the audio data instead of the size and modification
+    /// time. Entries survive retagging, but each file is read once more to compute the hash.
+    #[arg(long, requires = "cache")]
+    cache_audio_hash: bool,
+
+    /// Write an analysis report to the file ("-" for standard output).
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Format of the report. Defaults to the extension of the report file.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    report_format: Option<report::Format>,
+}
+
+impl Args {
+    fn reference(&self) -> f64 {
+        self.target.unwrap_or(self.preset.reference())
+    }
+
+    fn gate(&self) -> f64 {
+        self.gate.unwrap_or(self.preset.gate())
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Preset {
+    /// ReplayGain 2.0 (-18 LUFS).
+    #[value(name = "replaygain")]
+    ReplayGain,
+    /// EBU R128 (-23 LUFS).
+    #[value(name = "ebu-r128")]
+    EbuR128,
+    /// ATSC A/85 (-24 LKFS).
+    #[value(name = "atsc-a85")]
+    AtscA85,
+    /// Apple Music Sound Check (-16 LUFS).
+    AppleMusic,
+    /// Loudness normalization of major streaming services (-14 LUFS).
+    Streaming,
+}
+
+impl Preset {
+    fn reference(self) -> f64 {
+        match self {
+            Self::ReplayGain => -18.0,
+            Self::EbuR128 => -23.0,
+            Self::AtscA85 => -24.0,
+            Self::AppleMusic => -16.0,
+            Self::Streaming => -14.0,
+        }
+    }
+
+    fn gate(self) -> f64 {
+        -10.0
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Grouping {
+    /// Artist and album. Compilations are not grouped.
+    Artist,
+    /// Album artist (or artist if absent) and album.
+    AlbumArtist,
+    /// MusicBrainz album ID.
+    Musicbrainz,
+    /// Parent directory.
+    Directory,
+    /// Artist, album and disc number. Compilations are not grouped.
+    Disc,
+}
+
+impl Grouping {
+    fn group(self, path: &Path, file: &dyn AudioFile) -> Option<String> {
+        match self {
+            Self::Artist if !file.compilation() => {
+                Some(format!("{}\0{}", file.artist()?, file.album()?))
+            }
+            Self::AlbumArtist => {
+                let artist = match file.album_artist() {
+                    Some(artist) => artist,
+                    None if !file.compilation() => file.artist()?,
+                    None => return None,
+                };
+                Some(format!("{}\0{}", artist, file.album()?))
+            }
+            Self::Musicbrainz => file.musicbrainz_album_id().map(str::to_string),
+            Self::Directory => path.parent().map(|p| p.to_string_lossy().into_owned()),
+            Self::Disc if !file.compilation() => Some(format!(
+                "{}\0{}\0{}",
+                file.artist()?,
+                file.album()?,
+                file.disc().unwrap_or(1)
+            )),
+            _ => None,
+        }
+    }
 }
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    run(Args::parse());
+    run(Args::parse())
 }
 
 struct Entry {
     file: Box<dyn AudioFile + Send>,
+    group: Option<String>,
     aggregator: Option<Arc<Mutex<Aggregator>>>,
-    stats: Option<Stats>,
-    peak: Option<f64>,
+    analysis: Option<Analysis>,
+    error: Option<String>,
 }
 
 impl Entry {
-    fn new(file: Box<dyn AudioFile + Send>, aggregator: Option<Arc<Mutex<Aggregator>>>) -> Self {
+    fn new(
+        file: Box<dyn AudioFile + Send>,
+        group: Option<String>,
+        aggregator: Option<Arc<Mutex<Aggregator>>>,
+    ) -> Self {
         Self {
             file,
+            group,
             aggregator,
-            stats: None,
-            peak: None,
+            analysis: None,
+            error: None,
         }
     }
 }
 
-fn adjust_gain(gain: f64, base: f64) -> i32 {
-    (10.0_f64.powf(-gain / 10.0) * base).round().min(65534.0) as i32
+/// Returns the track gain, album gain, track peak and album peak in dB.
+fn decode_normalization(val: &ITunNorm) -> [f64; 4] {
+    [
+        val.track_gain(),
+        val.album_gain(),
+        bs1770::to_db(val.track_peak()),
+        bs1770::to_db(val.album_peak()),
+    ]
 }
 
-const DEFAULT_PARALLELISM: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(1) };
+/// Returns the descriptions of values in `stored` that deviate from `expected` by more than
+/// `tolerance` dB.
+fn verify_normalization(stored: Option<&str>, expected: &ITunNorm, tolerance: f64) -> Vec<String> {
+    const NAMES: [&str; 4] = ["track gain", "album gain", "track peak", "album peak"];
+
+    let stored = match stored.map(str::parse::<ITunNorm>) {
+        Some(Ok(stored)) => decode_normalization(&stored),
+        Some(Err(e)) => return vec![e.to_string()],
+        None => return vec!["missing iTunNORM".to_string()],
+    };
+    let expected = decode_normalization(expected);
+
+    NAMES
+        .iter()
+        .zip(stored.iter().zip(&expected))
+        .filter(|(_, (s, e))| !(*s == *e || (*s - *e).abs() <= tolerance))
+        .map(|(name, (s, e))| format!("{name} {s:.2} dB, expected {e:.2} dB"))
+        .collect()
+}
+
+const DEFAULT_PARALLELISM: NonZeroUsize = NonZeroUsize::MIN;
+
+fn run(args: Args) -> ExitCode {
+    if args.strip {
+        return strip(args);
+    }
 
-fn run(args: Args) {
     let para = thread::available_parallelism()
         .unwrap_or(DEFAULT_PARALLELISM)
         .get();
     let (tx1, rx1) = bounded(para);
     let (tx2, rx2) = unbounded();
+    let reference = args.reference();
+    let gate = args.gate();
+    let mode = args.peak;
+
+    let registry = match args.decoder {
+        Some(ref name) => Registry::default().with_backend(name),
+        None => Ok(Registry::default()),
+    };
+    let registry = match registry {
+        Ok(registry) => Arc::new(registry),
+        Err(e) => {
+            log::error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let cache = match args.cache {
+        Some(ref path) => match Cache::open(path, args.cache_audio_hash) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(e) => {
+                log::error!("{}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
     let mut threads = Vec::with_capacity(para);
     for _ in 0..para {
         let rx = rx1.clone();
         let tx = tx2.clone();
-        threads.push(thread::spawn(|| analyzer(rx, tx)));
+        let cache = cache.clone();
+        let registry = registry.clone();
+        threads.push(thread::spawn(move || {
+            analyzer(rx, tx, &registry, gate, mode, cache)
+        }));
     }
     drop(rx1);
     drop(tx2);
 
     let mut map = HashMap::new();
+    let mut report = Report::default();
     for path in &args.paths {
-        process(path, &mut map, &tx1);
+        process(path, &registry, &mut map, &tx1, &mut report, &args);
     }
     drop(tx1);
 
@@ -73,56 +301,224 @@ fn run(args: Args) {
         thread.join().unwrap();
     }
 
+    let mut cache = cache.map(|c| Arc::into_inner(c).unwrap());
+    let mut mismatched = false;
+    let mut albums = HashSet::new();
+
     for mut entry in rx2.iter() {
-        let track_gain = entry.stats.unwrap().get_mean(-10.0).to_gain();
-        let track_peak = (entry.peak.unwrap() * 32768.0) as i32;
-
-        let (album_gain, album_peak) = if let Some(ref aggregator) = entry.aggregator {
-            let guard = aggregator.lock().unwrap();
-            let gain = guard.stats.get_mean(-10.0).to_gain();
-            let peak = (guard.peak * 32768.0) as i32;
-            (gain, peak)
-        } else {
-            (track_gain, track_peak)
+        let mut record = Record {
+            group: entry.group.as_ref().map(|g| g.replace('\0', " / ")),
+            ..Record::new(entry.file.path())
         };
 
-        let normalization = format!(
-            " {:08X} {:08X} {:08X} {:08X} 00000000 00000000 {:08X} {:08X} 00000000 00000000",
-            adjust_gain(track_gain, 1000.0),
-            adjust_gain(album_gain, 1000.0),
-            adjust_gain(track_gain, 2500.0),
-            adjust_gain(album_gain, 2500.0),
+        if let Some(error) = entry.error {
+            record.error = Some(error);
+            report.push(record);
+            continue;
+        }
+
+        let analysis = entry.analysis.unwrap();
+        let loudness = analysis.stats.get_mean(gate);
+        let track_gain = loudness.to_gain(reference);
+        let track_peak = analysis.peak(mode);
+        let track_range = analysis.short_term.get_loudness_range();
+
+        let (album_gain, album_peak, album_range, album_true_peak) =
+            if let Some(ref aggregator) = entry.aggregator {
+                let guard = aggregator.lock().unwrap();
+                let album_loudness = guard.stats.get_mean(gate);
+                let album_range = guard.short_term.get_loudness_range();
+                if albums.insert(entry.group.clone()) {
+                    log::info!(
+                        "{}: album {album_loudness}, LRA {:.2} LU",
+                        record.group.as_deref().unwrap_or_default(),
+                        f64::from(album_range)
+                    );
+                }
+                (
+                    album_loudness.to_gain(reference),
+                    guard.peak(mode),
+                    album_range,
+                    guard.true_peak,
+                )
+            } else {
+                (track_gain, track_peak, track_range, analysis.true_peak)
+            };
+
+        let normalization = ITunNorm::new(track_gain, album_gain, track_peak, album_peak);
+
+        record.loudness = Some(loudness.into());
+        record.loudness_range = Some(track_range.into());
+        record.album_loudness_range = Some(album_range.into());
+        record.track_gain = Some(track_gain);
+        record.album_gain = Some(album_gain);
+        record.track_peak = Some(track_peak);
+        record.album_peak = Some(album_peak);
+        record.track_true_peak = analysis.true_peak.map(bs1770::to_db);
+        record.album_true_peak = album_true_peak.map(bs1770::to_db);
+        if entry.file.supports_normalization() {
+            record.normalization = Some(normalization.to_string());
+        }
+
+        let replaygain = ReplayGain {
+            track_gain,
             track_peak,
-            album_peak
-        );
-        entry.file.set_normalization(&normalization);
+            album_gain,
+            album_peak,
+            reference,
+        };
+
+        // Formats without iTunNORM always get ReplayGain tags.
+        let write_replaygain = args.replaygain || !entry.file.supports_normalization();
 
-        if let Err(e) = entry.file.save() {
-            log::error!("{}: {e}", entry.file.path().display());
+        if args.verify && !(entry.file.supports_normalization() && entry.file.writable()) {
+            log::warn!("{}: no iTunNORM to verify", entry.file.path().display());
+        } else if args.verify {
+            let mismatches =
+                verify_normalization(entry.file.normalization(), &normalization, args.tolerance);
+            for mismatch in &mismatches {
+                log::warn!("{}: {mismatch}", entry.file.path().display());
+            }
+            mismatched |= !mismatches.is_empty();
+            record.mismatch = Some(!mismatches.is_empty());
+        } else if !entry.file.writable() {
+            log::info!(
+                "{}: writing tags to this container is not supported, skipping",
+                entry.file.path().display()
+            );
+        } else if args.dry_run {
+            if entry.file.supports_normalization() {
+                log::info!(
+                    "{}: {:?} -> {:?}",
+                    entry.file.path().display(),
+                    entry.file.normalization().unwrap_or_default(),
+                    normalization.to_string()
+                );
+            }
+            if write_replaygain {
+                for (name, value) in entry.file.replaygain_tags(&replaygain) {
+                    log::info!("{}: {name}={value}", entry.file.path().display());
+                }
+            }
+        } else {
+            entry.file.set_normalization(&normalization.to_string());
+            if write_replaygain {
+                entry.file.set_replaygain(&replaygain);
+            }
+
+            if let Err(e) = entry.file.save() {
+                log::error!("{}: {e}", entry.file.path().display());
+                record.error = Some(e.to_string());
+            }
+        }
+
+        // Saving changes the size and modification time of the file, so this must come last.
+        if let Some(ref mut cache) = cache {
+            if let Err(e) = cache.insert(entry.file.path(), analysis) {
+                log::warn!("{}: {e}", entry.file.path().display());
+            }
         }
+
+        report.push(record);
+    }
+
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            log::error!("{}: {e}", args.cache.unwrap().display());
+        }
+    }
+
+    let failed = args.verify && (mismatched || report.has_errors());
+
+    if let Some(ref path) = args.report {
+        if let Err(e) = report.write(path, args.report_format) {
+            log::error!("{}: {e}", path.display());
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
 }
 
-fn process(path: &Path, map: &mut HashMap<String, Arc<Mutex<Aggregator>>>, tx: &Sender<Entry>) {
+fn strip(args: Args) -> ExitCode {
+    let (tx, rx) = unbounded();
+    let registry = Registry::default();
+    let mut map = HashMap::new();
+    let mut report = Report::default();
+    for path in &args.paths {
+        process(path, &registry, &mut map, &tx, &mut report, &args);
+    }
+    drop(tx);
+
+    for mut entry in rx.iter() {
+        let mut record = Record::new(entry.file.path());
+
+        // Non-short-circuiting so that both kinds of tags are removed.
+        let changed = entry.file.remove_normalization() | entry.file.remove_replaygain();
+        if !changed {
+            log::info!("{}: nothing to strip", entry.file.path().display());
+        } else {
+            match entry.file.save() {
+                Ok(_) => log::info!("{}: stripped", entry.file.path().display()),
+                Err(e) => {
+                    log::error!("{}: {e}", entry.file.path().display());
+                    record.error = Some(e.to_string());
+                }
+            }
+        }
+
+        report.push(record);
+    }
+
+    let failed = report.has_errors();
+
+    if let Some(ref path) = args.report {
+        if let Err(e) = report.write(path, args.report_format) {
+            log::error!("{}: {e}", path.display());
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn process(
+    path: &Path,
+    registry: &Registry,
+    map: &mut HashMap<String, Arc<Mutex<Aggregator>>>,
+    tx: &Sender<Entry>,
+    report: &mut Report,
+    args: &Args,
+) {
     let res = if path.is_dir() {
-        process_dir(path, map, tx)
+        process_dir(path, registry, map, tx, report, args)
     } else {
-        process_file(path, map, tx)
+        process_file(path, registry, map, tx, args)
     };
 
     if let Err(e) = res {
-        log::error!("{}: {e}", path.display())
+        log::error!("{}: {e}", path.display());
+        report.error(path, e);
     }
 }
 
 fn process_dir(
     path: &Path,
+    registry: &Registry,
     map: &mut HashMap<String, Arc<Mutex<Aggregator>>>,
     tx: &Sender<Entry>,
+    report: &mut Report,
+    args: &Args,
 ) -> Result<()> {
     for entry in fs::read_dir(path)? {
         let path = entry?.path();
-        process(&path, map, tx);
+        process(&path, registry, map, tx, report, args);
     }
 
     Ok(())
@@ -130,76 +526,206 @@ fn process_dir(
 
 fn process_file(
     path: &Path,
+    registry: &Registry,
     map: &mut HashMap<String, Arc<Mutex<Aggregator>>>,
     tx: &Sender<Entry>,
+    args: &Args,
 ) -> Result<()> {
-    let ext = match path.extension().and_then(|e| e.to_str()) {
-        Some(ext) => ext.to_lowercase(),
-        _ => return Ok(()),
+    let Some((format, decodable)) = registry.identify(path)? else {
+        return Ok(());
     };
+    // Stripping tags needs no decoder.
+    if !decodable && !args.strip {
+        log::warn!(
+            "{}: skipped, built without a decoder for {format:?}",
+            path.display()
+        );
+        return Ok(());
+    }
 
-    let file: Box<dyn AudioFile + Send> = match ext.as_str() {
-        "mp3" => Box::new(Mp3File::open(path)?),
-        #[cfg(any(target_os = "macos", target_os = "windows"))]
-        "m4a" => Box::new(M4aFile::open(path)?),
-        _ => return Ok(()),
+    let file: Box<dyn AudioFile + Send> = match format {
+        Format::Mpeg | Format::Wave | Format::Aiff | Format::Caf => Box::new(Id3File::open(path)?),
+        Format::Flac => Box::new(FlacFile::open(path)?),
+        Format::Vorbis => Box::new(OggFile::open(path)?),
+        Format::Opus => Box::new(OpusFile::open(path)?.with_output_gain(args.opus_output_gain)),
+        Format::Mp4 => Box::new(M4aFile::open(path)?),
     };
 
-    let aggregator = if !file.compilation() {
-        if let Some(artist) = file.artist() {
-            if let Some(album) = file.album() {
-                let group = format!("{}\0{}", artist, album);
-                let aggregator = map.entry(group).or_default();
-                Some(Arc::clone(aggregator))
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+    let group = args.group_by.group(path, file.as_ref());
+    let aggregator = group
+        .as_ref()
+        .map(|group| Arc::clone(map.entry(group.clone()).or_default()));
 
-    tx.send(Entry::new(file, aggregator))?;
+    tx.send(Entry::new(file, group, aggregator))?;
 
     Ok(())
 }
 
-fn analyzer(rx: Receiver<Entry>, tx: Sender<Entry>) {
-    'recv: for mut entry in rx.iter() {
-        let mut reader = match AudioReader::open(entry.file.path()) {
-            Ok(reader) => reader,
-            Err(e) => {
-                log::error!("{}: {e}", entry.file.path().display());
-                continue;
-            }
-        };
-
-        let mut analyzer = Analyzer::new(reader.sampling_rate(), reader.channels());
-        let (stats, peak) = loop {
-            match reader.read() {
-                Ok(sample) => match sample {
-                    Some(sample) => analyzer.add_sample(&sample),
-                    None => break analyzer.flush(),
-                },
+fn analyzer(
+    rx: Receiver<Entry>,
+    tx: Sender<Entry>,
+    registry: &Registry,
+    gate: f64,
+    mode: PeakMode,
+    cache: Option<Arc<Cache>>,
+) {
+    for mut entry in rx.iter() {
+        let cached = cache.as_ref().and_then(|c| c.get(entry.file.path(), mode));
+        let analysis = match cached {
+            Some(analysis) => analysis,
+            None => match analyze(registry, entry.file.path(), mode) {
+                Ok(analysis) => analysis,
                 Err(e) => {
                     log::error!("{}: {e}", entry.file.path().display());
-                    continue 'recv;
+                    entry.error = Some(e.to_string());
+                    let _ = tx.send(entry);
+                    continue;
                 }
-            }
+            },
         };
 
         if let Some(ref aggregator) = entry.aggregator {
-            aggregator.lock().unwrap().aggregate(&stats, peak);
+            aggregator.lock().unwrap().aggregate(&analysis);
         }
 
-        let loudness = stats.get_mean(-10.0);
+        let true_peak = analysis
+            .true_peak
+            .map(|p| format!(", TP {:.2} dBTP", bs1770::to_db(p)))
+            .unwrap_or_default();
+        log::info!(
+            "{}: {}, LRA {:.2} LU{true_peak}",
+            entry.file.path().display(),
+            analysis.stats.get_mean(gate),
+            f64::from(analysis.short_term.get_loudness_range())
+        );
 
-        entry.stats = Some(stats);
-        entry.peak = Some(peak);
-        log::info!("{}: {}", entry.file.path().display(), loudness);
+        entry.analysis = Some(analysis);
 
         let _ = tx.send(entry);
     }
 }
+
+/// Number of frames decoded and analyzed at a time.
+const BLOCK_FRAMES: usize = 4096;
+
+fn analyze(registry: &Registry, path: &Path, mode: PeakMode) -> Result<Analysis> {
+    let mut reader = registry.open(path)?;
+    log::debug!(
+        "{}: {} Hz, {:?}",
+        path.display(),
+        reader.sampling_rate(),
+        reader.channel_layout()
+    );
+    let mut analyzer = Analyzer::new(reader.sampling_rate(), &reader.channel_layout(), mode);
+    let mut buffer = Vec::new();
+    loop {
+        let (sampling_rate, channels) = (reader.sampling_rate(), reader.channels());
+        if (sampling_rate, channels) != (analyzer.sampling_rate(), analyzer.channels()) {
+            let layout = reader.channel_layout();
+            log::debug!(
+                "{}: format changed to {} Hz, {:?}",
+                path.display(),
+                sampling_rate,
+                layout
+            );
+            analyzer.set_format(sampling_rate, &layout);
+        }
+
+        buffer.resize(BLOCK_FRAMES * channels, 0.0);
+        let frames = reader.read_frames(&mut buffer)?;
+        if frames == 0 {
+            break;
+        }
+        analyzer.add_frames(&buffer[..frames * channels], reader.frame_layout());
+    }
+
+    Ok(analyzer.flush())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let stored = " 000003E8 000007D0 000009C4 00001388 00000000 00000000 00004000 00008000 00000000 00000000";
+        let expected = stored.parse::<ITunNorm>().unwrap();
+        assert_eq!(
+            decode_normalization(&expected).map(|v| (v * 100.0).round() / 100.0),
+            [0.0, -3.01, -6.02, 0.0]
+        );
+
+        assert!(verify_normalization(Some(stored), &expected, 0.0).is_empty());
+        assert_eq!(
+            verify_normalization(None, &expected, 0.5),
+            ["missing iTunNORM"]
+        );
+        assert_eq!(
+            verify_normalization(Some("foo"), &expected, 0.5),
+            ["invalid field in iTunNORM: \"foo\""]
+        );
+
+        let expected = " 00000320 000007D0 000007D0 00001388 00000000 00000000 00004000 00007000 00000000 00000000";
+        let expected = expected.parse::<ITunNorm>().unwrap();
+        assert_eq!(
+            verify_normalization(Some(stored), &expected, 0.5),
+            [
+                "track gain 0.00 dB, expected 0.97 dB",
+                "album peak 0.00 dB, expected -1.16 dB"
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_files() {
+        let dir = std::env::temp_dir().join(format!("chksound-{}-strip", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sample.flac");
+        fs::copy("test_data/sample.flac", &path).unwrap();
+        let args = |path: &Path| Args::parse_from(["chksound", "--strip", path.to_str().unwrap()]);
+
+        let mut file = FlacFile::open(&path).unwrap();
+        file.set_normalization(&ITunNorm::default().to_string());
+        file.save().unwrap();
+        let tagged = fs::read(&path).unwrap();
+        assert_eq!(strip(args(&path)), ExitCode::SUCCESS);
+        let stripped = fs::read(&path).unwrap();
+        assert_ne!(stripped, tagged);
+        assert!(FlacFile::open(&path).unwrap().normalization().is_none());
+
+        // Files without tags to remove are left alone.
+        assert_eq!(strip(args(&path)), ExitCode::SUCCESS);
+        assert_eq!(fs::read(&path).unwrap(), stripped);
+
+        let broken = dir.join("broken.flac");
+        fs::write(&broken, b"not a flac file").unwrap();
+        assert_eq!(strip(args(&dir)), ExitCode::FAILURE);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unwritable_container() {
+        let dir = std::env::temp_dir().join(format!("chksound-{}-caf", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sample.caf");
+        fs::copy("test_data/sample.caf", &path).unwrap();
+        let report = dir.join("report.json");
+        let args = Args::parse_from([
+            "chksound",
+            "--report",
+            report.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ]);
+
+        assert_eq!(run(args), ExitCode::SUCCESS);
+        let records: serde_json::Value =
+            serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+        assert!(records[0]["loudness"].is_number());
+        assert!(records[0]["error"].is_null());
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            fs::read("test_data/sample.caf").unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
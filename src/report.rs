@@ -0,0 +1,135 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct Record {
+    pub path: String,
+    pub group: Option<String>,
+    pub loudness: Option<f64>,
+    pub loudness_range: Option<f64>,
+    pub album_loudness_range: Option<f64>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_peak: Option<f64>,
+    pub track_true_peak: Option<f64>,
+    pub album_true_peak: Option<f64>,
+    pub normalization: Option<String>,
+    pub mismatch: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Report {
+    records: Vec<Record>,
+}
+
+impl Report {
+    pub fn push(&mut self, record: Record) {
+        self.records.push(record);
+    }
+
+    pub fn error(&mut self, path: &Path, error: impl ToString) {
+        self.push(Record {
+            error: Some(error.to_string()),
+            ..Record::new(path)
+        });
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.records.iter().any(|r| r.error.is_some())
+    }
+
+    pub fn write(mut self, path: &Path, format: Option<Format>) -> Result<()> {
+        self.records.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let format = format.unwrap_or_else(|| Format::from_path(path));
+        let mut writer: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stdout().lock())
+        } else {
+            Box::new(File::create(path)?)
+        };
+
+        match format {
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut writer, &self.records)?;
+                writeln!(writer)?;
+            }
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                for record in &self.records {
+                    writer.serialize(record)?;
+                }
+                writer.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_path() {
+        assert!(matches!(
+            Format::from_path(Path::new("report.CSV")),
+            Format::Csv
+        ));
+        assert!(matches!(
+            Format::from_path(Path::new("report.json")),
+            Format::Json
+        ));
+        assert!(matches!(Format::from_path(Path::new("-")), Format::Json));
+    }
+
+    #[test]
+    fn csv_record() {
+        let record = Record {
+            track_gain: Some(-1.5),
+            error: Some("oops".to_string()),
+            ..Record::new(Path::new("a.mp3"))
+        };
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(&record).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            "path,group,loudness,loudness_range,album_loudness_range,\
+             track_gain,album_gain,track_peak,album_peak,track_true_peak,album_true_peak,\
+             normalization,mismatch,error\n\
+             a.mp3,,,,,-1.5,,,,,,,,oops\n"
+        );
+    }
+}